@@ -67,6 +67,7 @@ cat .vendor/config.toml >> .cargo/config
 %install
 install -Dm0755 target/release/desktop-entry-daemon %{buildroot}/%{_libexecdir}/desktop-entry-daemon
 install -Dm0644 profile.d/desktop-entry-daemon.sh %{buildroot}/%{_sysconfdir}/profile.d/desktop-entry-daemon.sh
+install -Dm0644 res/desktop-entry-daemon.environment.d %{buildroot}/%{_environmentdir}/60-desktop-entry-daemon.conf
 install -Dm0644 systemd/desktop-entry-daemon.service %{buildroot}/%{_userunitdir}/desktop-entry-daemon.service
 
 
@@ -95,6 +96,7 @@ install -Dm0644 systemd/desktop-entry-daemon.service %{buildroot}/%{_userunitdir
 %{_libexecdir}/%{name}
 %{_userunitdir}/%{name}.service
 %{_sysconfdir}/profile.d/%{name}.sh
+%{_environmentdir}/60-%{name}.conf
 
 %changelog
 %autochangelog
\ No newline at end of file
@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::entry_management::EntryManager;
+
+/// an index of the desktop file ids installed in the xdg application directories, so
+/// duplicate appid checks are a lookup instead of a walk over every installed entry. kept
+/// current by `watch_app_index`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppIndex {
+    pub dirs: Vec<PathBuf>,
+    apps: HashMap<String, HashSet<PathBuf>>,
+}
+
+impl AppIndex {
+    /// indexes every `.desktop` file in `dirs` and their subdirectories
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        let mut index = Self {
+            dirs,
+            apps: HashMap::new(),
+        };
+        for dir in index.dirs.clone() {
+            index.insert_dir(&dir);
+        }
+        log::debug!("indexed {} app ids", index.apps.len());
+        index
+    }
+
+    /// whether an entry with the desktop file id `appid` is installed
+    pub fn contains(&self, appid: &str) -> bool {
+        self.apps.contains_key(appid)
+    }
+
+    /// the files the desktop file id `appid` is installed at
+    pub fn paths(&self, appid: &str) -> Vec<PathBuf> {
+        self.apps
+            .get(appid)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// every indexed app id and the files it is installed at
+    pub fn apps(&self) -> &HashMap<String, HashSet<PathBuf>> {
+        &self.apps
+    }
+
+    /// adds the entry at `path` if it is a `.desktop` file in one of the indexed directories
+    pub fn insert(&mut self, path: &Path) {
+        let Some(root) = self
+            .dirs
+            .iter()
+            .filter(|x| path.starts_with(x))
+            .max_by_key(|x| x.components().count())
+        else {
+            return;
+        };
+        if let Some(appid) = desktop_file_id(root, path) {
+            self.apps.entry(appid).or_default().insert(path.to_owned());
+        }
+    }
+
+    /// removes the entry at `path`, or every entry under it if it was a directory
+    pub fn remove(&mut self, path: &Path) {
+        self.apps.retain(|_, paths| {
+            paths.retain(|x| !x.starts_with(path));
+            !paths.is_empty()
+        });
+    }
+
+    /// adds every `.desktop` file under `dir`, returning the directories that were walked
+    fn insert_dir(&mut self, dir: &Path) -> Vec<PathBuf> {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut dirs = vec![dir.to_owned()];
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.extend(self.insert_dir(&path));
+            } else {
+                self.insert(&path);
+            }
+        }
+        dirs
+    }
+}
+
+/// the desktop file id of `path` without its `.desktop` extension, if it is a `.desktop` file
+/// under the indexed directory `root`. the spec makes it the path relative to `root`, with
+/// `/` replaced by `-`, so `root/vendor/foo.desktop` is `vendor-foo`
+fn desktop_file_id(root: &Path, path: &Path) -> Option<String> {
+    if path.extension().is_none_or(|ext| ext != "desktop") {
+        return None;
+    }
+    let relative = path.strip_prefix(root).ok()?.with_extension("");
+    let components = relative
+        .components()
+        .map(|x| x.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+    Some(components.join("-"))
+}
+
+/// the inotify watches `watch_app_index` keeps on the indexed directories
+struct AppWatches {
+    inotify: Inotify,
+    /// indexed directories and their subdirectories
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    /// the nearest existing parents of indexed directories that don't exist yet, watched until
+    /// they appear
+    parents: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl AppWatches {
+    const MASK: WatchMask = WatchMask::CREATE
+        .union(WatchMask::DELETE)
+        .union(WatchMask::MOVED_FROM)
+        .union(WatchMask::MOVED_TO);
+
+    /// watches `dir`, an indexed directory or one of its subdirectories
+    fn watch(&mut self, dir: PathBuf) {
+        match self.inotify.watches().add(&dir, Self::MASK) {
+            Ok(wd) => {
+                self.dirs.insert(wd, dir);
+            }
+            Err(e) => log::warn!("could not watch {:?} for app changes: {:?}", dir, e),
+        }
+    }
+
+    /// indexes and watches the indexed directories of `index` that aren't watched yet, and
+    /// watches the nearest existing parent of those that don't exist
+    fn refresh(&mut self, index: &mut AppIndex) {
+        let mut stale = std::mem::take(&mut self.parents);
+        for root in index.dirs.clone() {
+            let mut parent: Option<(WatchDescriptor, PathBuf)> = None;
+            loop {
+                if root.is_dir() {
+                    if !self.dirs.values().any(|x| *x == root) {
+                        // watched before it is walked, so nothing created in between is missed
+                        self.watch(root.clone());
+                        for dir in index.insert_dir(&root).into_iter().skip(1) {
+                            self.watch(dir);
+                        }
+                    }
+                    stale.extend(parent);
+                    break;
+                }
+                // checked again once the parent is watched, in case it appeared in between
+                let Some(nearest) = root.ancestors().skip(1).find(|x| x.is_dir()) else {
+                    stale.extend(parent);
+                    break;
+                };
+                if parent.as_ref().is_some_and(|(_, x)| x == nearest) {
+                    self.parents.extend(parent);
+                    break;
+                }
+                match self.inotify.watches().add(nearest, Self::MASK) {
+                    Ok(wd) => stale.extend(parent.replace((wd, nearest.to_owned()))),
+                    Err(e) => {
+                        log::warn!("could not watch {:?} for app changes: {:?}", nearest, e);
+                        stale.extend(parent);
+                        break;
+                    }
+                }
+            }
+        }
+        for (wd, _) in stale {
+            if !self.parents.contains_key(&wd) && !self.dirs.contains_key(&wd) {
+                let _ = self.inotify.watches().remove(wd);
+            }
+        }
+    }
+
+    /// indexes every indexed directory again, after inotify dropped events
+    fn rescan(&mut self, index: &mut AppIndex) {
+        index.apps.clear();
+        // watching a directory again keeps its watch, so no events are missed while walking
+        self.dirs.clear();
+        self.refresh(index);
+    }
+}
+
+/// keeps the app index of `manager` current by watching the indexed directories with
+/// inotify. runs on its own thread, since inotify reads block
+pub fn watch_app_index(manager: Arc<Mutex<EntryManager>>) -> std::io::Result<()> {
+    let mut watches = AppWatches {
+        inotify: Inotify::init()?,
+        dirs: HashMap::new(),
+        parents: HashMap::new(),
+    };
+    // indexed again, for changes made before the watches were in place
+    watches.rescan(&mut task::block_on(manager.lock()).app_index);
+    log::info!(
+        "Watching {} directories for app changes!",
+        watches.dirs.len()
+    );
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match watches.inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events
+                    .map(|x| (x.wd, x.mask, x.name.map(|x| x.to_owned())))
+                    .collect::<Vec<_>>(),
+                Err(e) => {
+                    log::error!("Something went wrong when watching for app changes {:?}", e);
+                    return;
+                }
+            };
+            let mut manager_lock = task::block_on(manager.lock());
+            let index = &mut manager_lock.app_index;
+            let mut refresh = false;
+            for (wd, event_mask, name) in events {
+                if event_mask.contains(EventMask::Q_OVERFLOW) {
+                    log::warn!("missed app changes, indexing every app again");
+                    watches.rescan(index);
+                    refresh = false;
+                    break;
+                }
+                if event_mask.contains(EventMask::IGNORED) {
+                    // the directory is gone. indexed directories are waited for again
+                    if let Some(dir) = watches.dirs.remove(&wd) {
+                        refresh |= index.dirs.contains(&dir);
+                    }
+                    refresh |= watches.parents.remove(&wd).is_some();
+                    continue;
+                }
+                refresh |= watches.parents.contains_key(&wd);
+                let (Some(dir), Some(name)) = (watches.dirs.get(&wd), name) else {
+                    continue;
+                };
+                let path = dir.join(name);
+                if event_mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    if event_mask.contains(EventMask::ISDIR) {
+                        for dir in index.insert_dir(&path) {
+                            watches.watch(dir);
+                        }
+                    } else {
+                        index.insert(&path);
+                    }
+                } else {
+                    index.remove(&path);
+                }
+            }
+            if refresh {
+                watches.refresh(index);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        test_support::{entry_manager, eventually, TempDir, ENTRY},
+    };
+
+    /// a manager indexing `dirs`, with its index watched
+    fn watched_manager(dir: &Path, dirs: Vec<PathBuf>) -> Arc<Mutex<EntryManager>> {
+        let mut manager = entry_manager(dir, Config::default());
+        manager.app_index = AppIndex::new(dirs);
+        let manager = Arc::new(Mutex::new(manager));
+        watch_app_index(manager.clone()).unwrap();
+        manager
+    }
+
+    #[async_std::test]
+    async fn directories_are_indexed_once_they_appear() {
+        let dir = TempDir::new();
+        let applications = dir.path().join("share/applications");
+        let manager = watched_manager(dir.path(), vec![applications.clone()]);
+        let indexed = async |appid: &str| manager.lock().await.app_index.contains(appid);
+
+        fs::create_dir_all(applications.join("vendor")).unwrap();
+        fs::write(applications.join("vendor/foo.desktop"), ENTRY).unwrap();
+        assert!(eventually(async || indexed("vendor-foo").await).await);
+        assert!(!indexed("foo").await);
+
+        // and waited for again when they are removed
+        fs::remove_dir_all(dir.path().join("share")).unwrap();
+        assert!(eventually(async || !indexed("vendor-foo").await).await);
+        fs::create_dir_all(&applications).unwrap();
+        fs::write(applications.join("bar.desktop"), ENTRY).unwrap();
+        assert!(eventually(async || indexed("bar").await).await);
+    }
+
+    #[async_std::test]
+    async fn everything_is_indexed_again_when_events_are_dropped() {
+        let dir = TempDir::new();
+        let applications = dir.path().join("applications");
+        fs::create_dir_all(&applications).unwrap();
+        let manager = watched_manager(dir.path(), vec![applications.clone()]);
+
+        // the watcher waits on the lock while more changes are made than inotify queues
+        let count = 20000;
+        {
+            let _lock = manager.lock().await;
+            for i in 0..count {
+                fs::write(applications.join(format!("app{}.desktop", i)), "").unwrap();
+            }
+        }
+        assert!(eventually(async || manager.lock().await.app_index.apps().len() == count).await);
+    }
+}
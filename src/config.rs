@@ -0,0 +1,163 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{entry_management::Lifetime, storage::StorageBackend, validation::Threshold};
+
+/// user configuration for the daemon, read from `config.ron` next to the cache. every field
+/// has a default, so the file only needs to contain what differs from it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub exec_policy: ExecPolicy,
+    /// link entries and icons into `$XDG_DATA_HOME` when the lifetime directories are missing
+    /// from the session's `XDG_DATA_DIRS`
+    pub symlink_fallback: bool,
+    pub duplicate_policy: DuplicatePolicy,
+    pub validation: ValidationPolicy,
+    pub storage: StorageBackend,
+}
+
+/// decides which registered resources may make the desktop run commands on its own
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExecPolicy {
+    /// autostart entries are only accepted with lifetimes that end with the session
+    #[default]
+    Restricted,
+    /// autostart entries are accepted with any lifetime, including ones that outlive the
+    /// session
+    Permissive,
+}
+
+/// what to do with an entry whose appid is already installed, depending on where the
+/// installed copy came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicatePolicy {
+    /// copies registered through the daemon by the same owner, i.e. before a crash
+    pub same_owner: DuplicateAction,
+    /// copies registered through the daemon by another owner
+    pub other_owner: DuplicateAction,
+    /// copies in the user's data directory
+    pub user: DuplicateAction,
+    /// copies anywhere else, i.e. installed by the system
+    pub system: DuplicateAction,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        Self {
+            same_owner: DuplicateAction::Replace,
+            other_owner: DuplicateAction::Share,
+            user: DuplicateAction::Namespace,
+            system: DuplicateAction::Namespace,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicateAction {
+    /// remove the installed copy if the daemon manages it, then register the entry under
+    /// its appid. copies the daemon doesn't manage are left alone and shadow or are shadowed
+    /// by the new entry depending on the `XDG_DATA_DIRS` order
+    Replace,
+    /// refuse the entry with a duplicate app id error
+    Reject,
+    /// save the entry under a file name namespaced by its owner, if the owner chose a
+    /// namespace mode, otherwise refuse it
+    Namespace,
+    /// add a claim on the installed copy if the daemon manages it, so it's kept until every
+    /// owner that registered it is done with it. copies the daemon doesn't manage are
+    /// handled like `Namespace`
+    Share,
+}
+
+/// how strictly desktop entries are checked against the spec, per kind of lifetime
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationPolicy {
+    pub process: Threshold,
+    pub session: Threshold,
+    pub persistent: Threshold,
+    pub expiring: Threshold,
+    pub unit: Threshold,
+    pub path: Threshold,
+    pub container: Threshold,
+    pub lease: Threshold,
+}
+
+impl ValidationPolicy {
+    /// the threshold for entries registered with `lifetime`. compositions use the strictest
+    /// threshold of the lifetimes they're made of
+    pub fn threshold(&self, lifetime: &Lifetime) -> Threshold {
+        match lifetime {
+            Lifetime::Process(_) => self.process,
+            Lifetime::Session(_) => self.session,
+            Lifetime::Persistent(_) => self.persistent,
+            Lifetime::Expiring { .. } => self.expiring,
+            Lifetime::Unit(_) => self.unit,
+            Lifetime::Path { .. } => self.path,
+            Lifetime::Container { .. } => self.container,
+            Lifetime::Lease(_) => self.lease,
+            Lifetime::AnyOf(lifetimes) | Lifetime::AllOf(lifetimes) => lifetimes
+                .iter()
+                .map(|x| self.threshold(x))
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Config {
+    /// loads the configuration at `path`, using the defaults if it is missing or invalid
+    pub fn load(path: &Path) -> Self {
+        let Ok(str_data) = fs::read_to_string(path) else {
+            log::debug!("no config at {:?}, using the defaults", path);
+            return Self::default();
+        };
+        match ron::from_str(&str_data) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!(
+                    "could not parse config {:?}, using the defaults: {}",
+                    path,
+                    e
+                );
+                Self::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_kind_of_lifetime_has_its_own_threshold() {
+        let policy = ValidationPolicy {
+            expiring: Threshold::Warning,
+            unit: Threshold::Error,
+            ..Default::default()
+        };
+        let expiring = Lifetime::Expiring {
+            owner: "me".to_string(),
+            deadline: 0,
+        };
+        let unit = Lifetime::Unit("foo.service".to_string());
+        assert_eq!(policy.threshold(&expiring), Threshold::Warning);
+        assert_eq!(policy.threshold(&unit), Threshold::Error);
+        assert_eq!(
+            policy.threshold(&Lifetime::Persistent("me".to_string())),
+            Threshold::Off
+        );
+        assert_eq!(
+            policy.threshold(&Lifetime::AnyOf(vec![unit.clone(), expiring])),
+            Threshold::Warning
+        );
+        assert_eq!(
+            policy.threshold(&Lifetime::AllOf(vec![Lifetime::Process(1), unit])),
+            Threshold::Error
+        );
+    }
+}
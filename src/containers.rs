@@ -0,0 +1,426 @@
+use std::{
+    env,
+    fmt::Display,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use async_std::{
+    io::{prelude::BufReadExt, BufReader, Lines, WriteExt},
+    os::unix::net::UnixStream,
+    stream::StreamExt,
+    sync::{Arc, Mutex},
+    task,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::entry_management::{EntryManager, Lifetime};
+
+/// asks for the engine's events about containers being removed, which it streams as json
+/// objects, one per line. the filter is `{"type":["container"],"event":["destroy"]}`
+const DESTROY_EVENTS: &str = "GET /events?filters=%7B%22type%22%3A%5B%22container%22%5D%2C%22event%22%3A%5B%22destroy%22%5D%7D HTTP/1.0\r\nHost: localhost\r\n\r\n";
+
+/// how long to wait before connecting to an engine again, after it couldn't be reached or
+/// stopped sending events. while no containers of an engine are watched, the connection to
+/// it is closed after this long, so podman's api service can exit when it's idle
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a container engine serving the docker api on a local unix socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum ContainerEngine {
+    Podman,
+    Docker,
+}
+
+impl ContainerEngine {
+    /// the socket the engine's api listens on. `CONTAINER_HOST` for podman and `DOCKER_HOST`
+    /// for docker override it when they are `unix://` urls, as they do for the engines' own
+    /// clients
+    pub fn socket(&self) -> PathBuf {
+        let (var, default) = match self {
+            ContainerEngine::Podman => (
+                "CONTAINER_HOST",
+                match env::var("XDG_RUNTIME_DIR") {
+                    Ok(runtime_dir) => PathBuf::from(runtime_dir).join("podman/podman.sock"),
+                    Err(_) => PathBuf::from("/run/podman/podman.sock"),
+                },
+            ),
+            ContainerEngine::Docker => ("DOCKER_HOST", PathBuf::from("/var/run/docker.sock")),
+        };
+        env::var(var)
+            .ok()
+            .and_then(|x| x.strip_prefix("unix://").map(PathBuf::from))
+            .unwrap_or(default)
+    }
+
+    /// whether the container with the name or id `container` exists. errors if the engine
+    /// couldn't be asked, i.e. because it isn't running
+    pub async fn container_exists(&self, container: &str) -> std::io::Result<bool> {
+        container_exists(&self.socket(), container).await
+    }
+}
+
+impl Display for ContainerEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerEngine::Podman => write!(f, "podman"),
+            ContainerEngine::Docker => write!(f, "docker"),
+        }
+    }
+}
+
+impl FromStr for ContainerEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "podman" => Ok(ContainerEngine::Podman),
+            "docker" => Ok(ContainerEngine::Docker),
+            _ => Err(format!("{} is not a supported container engine", s)),
+        }
+    }
+}
+
+/// whether `container` can be a container name or id. both are limited to characters that
+/// are safe to put in a request path
+pub fn valid_container(container: &str) -> bool {
+    !container.is_empty()
+        && container
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '_' | '.' | '-'))
+}
+
+/// whether the container with the name or id `container` exists, asking the engine serving
+/// its api on `socket`. errors if the engine couldn't be asked or gave an unexpected answer
+pub async fn container_exists(socket: &Path, container: &str) -> std::io::Result<bool> {
+    let mut stream = UnixStream::connect(socket).await?;
+    stream
+        .write_all(
+            format!(
+                "GET /containers/{}/json HTTP/1.0\r\nHost: localhost\r\n\r\n",
+                container
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(true),
+        Some("404") => Ok(false),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected response: {:?}", status.trim()),
+        )),
+    }
+}
+
+/// the events the engine serving its api on `socket` sends about containers being removed,
+/// one line each
+async fn destroy_events(socket: &Path) -> std::io::Result<Lines<BufReader<UnixStream>>> {
+    let mut stream = UnixStream::connect(socket).await?;
+    stream.write_all(DESTROY_EVENTS.as_bytes()).await?;
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status).await?;
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected response: {:?}", status.trim()),
+        ));
+    }
+    // the headers end with an empty line
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(reader.lines())
+}
+
+/// removes the resources of containers that `engine`, serving its api on `socket`, has
+/// deleted. the engine's events are followed while any of its containers are watched. every
+/// container is checked when they're connected, which catches containers deleted in the
+/// meantime, and after that whenever an event names it
+pub async fn watch_containers(
+    manager: Arc<Mutex<EntryManager>>,
+    engine: ContainerEngine,
+    socket: PathBuf,
+) {
+    log::info!("Watching if containers are deleted from {}!", engine);
+    loop {
+        if watched_containers(&manager, engine, |_| true)
+            .await
+            .is_empty()
+        {
+            task::sleep(RECONNECT_INTERVAL).await;
+            continue;
+        }
+        let mut events = match destroy_events(&socket).await {
+            Ok(events) => events,
+            Err(e) => {
+                log::debug!("could not follow events from {}: {}", engine, e);
+                task::sleep(RECONNECT_INTERVAL).await;
+                continue;
+            }
+        };
+        check_containers(&manager, engine, &socket, |_| true).await;
+        loop {
+            match async_std::future::timeout(RECONNECT_INTERVAL, events.next()).await {
+                Err(_) => {
+                    if watched_containers(&manager, engine, |_| true)
+                        .await
+                        .is_empty()
+                    {
+                        break;
+                    }
+                }
+                Ok(Some(Ok(event))) => {
+                    // names and ids are only compared with the event's text, as the engine is
+                    // asked about the container before anything is removed
+                    check_containers(&manager, engine, &socket, |x| event.contains(x)).await;
+                }
+                Ok(_) => {
+                    log::debug!("{} stopped sending events", engine);
+                    task::sleep(RECONNECT_INTERVAL).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// the container lifetimes of `engine` with a name or id `filter` accepts
+async fn watched_containers(
+    manager: &Mutex<EntryManager>,
+    engine: ContainerEngine,
+    filter: impl Fn(&str) -> bool,
+) -> Vec<Lifetime> {
+    manager
+        .lock()
+        .await
+        .live_leaves()
+        .into_iter()
+        .filter(|x| {
+            matches!(x, Lifetime::Container { engine: e, container } if *e == engine && filter(container))
+        })
+        .collect()
+}
+
+/// asks the engine about the containers with a name or id `filter` accepts, and removes the
+/// resources of those it says don't exist. if it can't be asked, the resources are kept
+async fn check_containers(
+    manager: &Mutex<EntryManager>,
+    engine: ContainerEngine,
+    socket: &Path,
+    filter: impl Fn(&str) -> bool,
+) {
+    // the manager isn't locked while asking the engine, which may take a while
+    for lifetime in watched_containers(manager, engine, filter).await {
+        let Lifetime::Container { container, .. } = &lifetime else {
+            continue;
+        };
+        match container_exists(socket, container).await {
+            Ok(true) => {}
+            Ok(false) => {
+                log::info!(
+                    "Container {} was deleted from {}! Removing associated entries...",
+                    container,
+                    engine
+                );
+                let mut manager_lock = manager.lock().await;
+                if manager_lock.end_lifetime(lifetime.clone()).is_err() {
+                    log::error!(
+                        "Something went wrong when removing lifetime of container {}",
+                        container
+                    );
+                }
+                if manager_lock.save_cache().is_err() {
+                    log::error!(
+                        "Something went wrong when saving the cache to disk {}",
+                        container
+                    );
+                }
+            }
+            Err(e) => log::debug!("could not ask {} about {}: {}", engine, container, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex as SyncMutex};
+
+    use async_std::{
+        channel::{self, Receiver, Sender},
+        os::unix::net::UnixListener,
+    };
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_support::{entry_manager, eventually, TempDir, ENTRY},
+    };
+
+    /// stands in for a container engine's api. containers answer with the status in
+    /// `containers`, or with garbage if it's empty, and other containers don't exist.
+    /// lines sent to `events` are streamed to clients following the events
+    struct FakeEngine {
+        socket: PathBuf,
+        containers: Arc<SyncMutex<HashMap<String, &'static str>>>,
+        events: Sender<String>,
+    }
+
+    impl FakeEngine {
+        fn new(dir: &Path) -> Self {
+            let socket = dir.join("engine.sock");
+            let listener =
+                UnixListener::from(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+            let containers = Arc::new(SyncMutex::new(HashMap::new()));
+            let (events, receiver) = channel::unbounded();
+            let c = containers.clone();
+            task::spawn(async move {
+                while let Some(Ok(stream)) = listener.incoming().next().await {
+                    task::spawn(Self::serve(stream, c.clone(), receiver.clone()));
+                }
+            });
+            Self {
+                socket,
+                containers,
+                events,
+            }
+        }
+
+        async fn serve(
+            mut stream: UnixStream,
+            containers: Arc<SyncMutex<HashMap<String, &'static str>>>,
+            events: Receiver<String>,
+        ) {
+            let mut request = String::new();
+            BufReader::new(stream.clone())
+                .read_line(&mut request)
+                .await
+                .unwrap();
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            if path.starts_with("/events?") {
+                stream
+                    .write_all(b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n")
+                    .await
+                    .unwrap();
+                while let Ok(event) = events.recv().await {
+                    stream
+                        .write_all(format!("{}\n", event).as_bytes())
+                        .await
+                        .unwrap();
+                }
+                return;
+            }
+            let container = path
+                .strip_prefix("/containers/")
+                .and_then(|x| x.strip_suffix("/json"))
+                .unwrap_or_default();
+            let response = match containers.lock().unwrap().get(container) {
+                Some(&"") => "garbage\r\n\r\n".to_string(),
+                Some(status) => format!("HTTP/1.0 {}\r\n\r\n", status),
+                None => "HTTP/1.0 404 Not Found\r\n\r\n".to_string(),
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+
+        fn set(&self, container: &str, status: &'static str) {
+            self.containers
+                .lock()
+                .unwrap()
+                .insert(container.to_string(), status);
+        }
+    }
+
+    #[async_std::test]
+    async fn only_not_found_means_the_container_is_gone() {
+        let dir = TempDir::new();
+        let engine = FakeEngine::new(dir.path());
+        engine.set("running", "200 OK");
+        engine.set("failing", "500 Internal Server Error");
+        engine.set("garbled", "");
+        assert!(container_exists(&engine.socket, "running").await.unwrap());
+        assert!(!container_exists(&engine.socket, "removed").await.unwrap());
+        assert!(container_exists(&engine.socket, "failing").await.is_err());
+        assert!(container_exists(&engine.socket, "garbled").await.is_err());
+        assert!(
+            container_exists(&dir.path().join("missing.sock"), "running")
+                .await
+                .is_err()
+        );
+    }
+
+    #[async_std::test]
+    async fn container_lifetimes_end_when_the_engine_removes_the_container() {
+        let dir = TempDir::new();
+        let engine = FakeEngine::new(dir.path());
+        engine.set("running", "200 OK");
+        engine.set("failing", "500 Internal Server Error");
+        engine.set("garbled", "");
+        let manager = Arc::new(Mutex::new(entry_manager(dir.path(), Config::default())));
+        for container in ["running", "removed", "failing", "garbled"] {
+            let lifetime = Lifetime::Container {
+                engine: ContainerEngine::Podman,
+                container: container.to_string(),
+            };
+            manager
+                .lock()
+                .await
+                .register_entry(ENTRY, &format!("com.example.{}", container), lifetime)
+                .unwrap();
+        }
+        let registered = |container: &'static str| {
+            let manager = manager.clone();
+            async move {
+                let appid = format!("com.example.{}", container);
+                manager.lock().await.entry_for_appid(&appid).is_some()
+            }
+        };
+        task::spawn(watch_containers(
+            manager.clone(),
+            ContainerEngine::Podman,
+            engine.socket.clone(),
+        ));
+
+        // containers deleted before the events were followed are caught when they connect
+        assert!(eventually(async || !registered("removed").await).await);
+        task::sleep(Duration::from_secs(1)).await;
+
+        // the engine is only asked again about containers an event names
+        engine.set("running", "404 Not Found");
+        engine
+            .events
+            .send(r#"{"Type":"container","Action":"destroy","Actor":{"ID":"0123abcd","Attributes":{"name":"other"}}}"#.to_string())
+            .await
+            .unwrap();
+        task::sleep(Duration::from_secs(1)).await;
+        assert!(registered("running").await);
+        engine
+            .events
+            .send(r#"{"Type":"container","Action":"destroy","Actor":{"ID":"4567ef01","Attributes":{"name":"running"}}}"#.to_string())
+            .await
+            .unwrap();
+        assert!(eventually(async || !registered("running").await).await);
+
+        // errors and unexpected answers keep the resources
+        for container in ["failing", "garbled"] {
+            engine
+                .events
+                .send(format!(
+                    r#"{{"Actor":{{"Attributes":{{"name":"{}"}}}}}}"#,
+                    container
+                ))
+                .await
+                .unwrap();
+        }
+        task::sleep(Duration::from_secs(1)).await;
+        assert!(registered("failing").await);
+        assert!(registered("garbled").await);
+    }
+}
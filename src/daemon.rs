@@ -1,5 +1,8 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use async_std::sync::Arc;
 use async_std::sync::Mutex;
@@ -7,26 +10,165 @@ use zbus::fdo::DBusProxy;
 use zbus::message::Header;
 use zbus::names::BusName;
 use zbus::proxy::CacheProperties;
-use zbus::{interface, Connection};
+use zbus::{interface, Connection, SignalContext};
 
-use crate::entry_management::{EntryManager, EntryManagerError, Lifetime};
+use crate::entry_management::{
+    update_mime_databases, EntryManager, EntryManagerError, IconContext, Lifetime, NamespaceMode,
+};
+use crate::validation::{self, Diagnostic};
 
 pub struct Daemon {
     pub entry_manager: Arc<Mutex<EntryManager>>,
+    pub missing_data_dirs: Vec<String>,
 }
 
-impl Into<zbus::fdo::Error> for EntryManagerError {
-    fn into(self) -> zbus::fdo::Error {
-        match self {
-            Self::EntryValidation(e) => zbus::fdo::Error::InvalidArgs(e.to_string()),
-            Self::IO(e) => zbus::fdo::Error::IOError(e.to_string()),
-            Self::IconValidation(e) => zbus::fdo::Error::InvalidArgs(e.to_string()),
-            Self::PathCollision(p) => zbus::fdo::Error::FileExists(p.display().to_string()),
-            Self::Ron(r) => zbus::fdo::Error::IOError(r.to_string()),
+impl From<EntryManagerError> for zbus::fdo::Error {
+    fn from(value: EntryManagerError) -> Self {
+        match value {
+            EntryManagerError::EntryValidation(e) => zbus::fdo::Error::InvalidArgs(e.to_string()),
+            EntryManagerError::IO(e) => zbus::fdo::Error::IOError(e.to_string()),
+            EntryManagerError::IconValidation(e) => zbus::fdo::Error::InvalidArgs(e.to_string()),
+            EntryManagerError::MimeValidation(e) => zbus::fdo::Error::InvalidArgs(e),
+            EntryManagerError::PathCollision(p) => {
+                zbus::fdo::Error::FileExists(p.display().to_string())
+            }
+            EntryManagerError::UnknownAppID(appid) => {
+                zbus::fdo::Error::InvalidArgs(format!("{} is not a registered app id", appid))
+            }
+            EntryManagerError::ServiceValidation(e) => zbus::fdo::Error::InvalidArgs(e),
+            EntryManagerError::PolicyDenied(reason) => zbus::fdo::Error::AccessDenied(reason),
+            EntryManagerError::UnknownOwner(owner) => {
+                zbus::fdo::Error::InvalidArgs(format!("{} has no matching resources", owner))
+            }
+            EntryManagerError::InvalidPath(path) => zbus::fdo::Error::InvalidArgs(format!(
+                "{} is not an absolute path to an existing file",
+                path.display()
+            )),
+            EntryManagerError::InvalidContainer(e) => zbus::fdo::Error::InvalidArgs(e),
+            EntryManagerError::InvalidLifetime(e) => {
+                zbus::fdo::Error::InvalidArgs(format!("invalid lifetime: {}", e))
+            }
+            EntryManagerError::UnknownLease(id) => {
+                zbus::fdo::Error::InvalidArgs(format!("there is no lease {}, or it has lapsed", id))
+            }
+            EntryManagerError::NotRegistered(path) => {
+                zbus::fdo::Error::Failed(format!("{} is no longer registered", path.display()))
+            }
+            EntryManagerError::Ron(r) => zbus::fdo::Error::IOError(r.to_string()),
+            #[cfg(feature = "sqlite")]
+            EntryManagerError::Sqlite(e) => zbus::fdo::Error::IOError(e.to_string()),
         }
     }
 }
 
+/// `diagnostics` as (line, severity, message), where severity is "error" or "warning"
+fn diagnostic_tuples(diagnostics: Vec<Diagnostic>) -> Vec<(u32, String, String)> {
+    diagnostics
+        .into_iter()
+        .map(|x| (x.line as u32, x.severity.to_string(), x.message))
+        .collect()
+}
+
+/// the pid of the process that sent the message with header `hdr`
+async fn caller_pid(hdr: &Header<'_>, conn: &Connection) -> u32 {
+    let dbus_proxy = DBusProxy::builder(conn)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+        .unwrap();
+    dbus_proxy
+        .get_connection_credentials(BusName::Unique(hdr.sender().unwrap().to_owned()))
+        .await
+        .unwrap()
+        .process_id()
+        .unwrap()
+}
+
+/// the lifetime written in ron as `lifetime`, i.e.
+/// `AllOf([Process(1234), Expiring(owner: "me", deadline: 1700000000)])`
+async fn parse_lifetime(lifetime: &str) -> zbus::fdo::Result<Lifetime> {
+    let lifetime: Lifetime =
+        ron::from_str(lifetime).map_err(|e| EntryManagerError::InvalidLifetime(e.to_string()))?;
+    lifetime.check()?;
+    check_containers(&lifetime).await?;
+    Ok(lifetime)
+}
+
+/// emits `LifetimeChanged` for resources moved from `old` to `new`, now at `paths`
+async fn notify_lifetime_changed(
+    ctxt: &SignalContext<'_>,
+    old: &Lifetime,
+    new: &Lifetime,
+    paths: Vec<PathBuf>,
+) -> zbus::fdo::Result<()> {
+    let ron = |x: &Lifetime| ron::to_string(x).map_err(EntryManagerError::from);
+    Daemon::lifetime_changed(
+        ctxt,
+        &ron(old)?,
+        &ron(new)?,
+        paths
+            .iter()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// emits `PropertiesChanged` for `LeaseExpirations` on the daemon served on `connection`, for
+/// changes the daemon made on its own, i.e. when leases lapse
+pub async fn notify_lease_expirations(connection: &Connection) -> zbus::Result<()> {
+    let iface = connection
+        .object_server()
+        .interface::<_, Daemon>("/io/ryanabx/DesktopEntry")
+        .await?;
+    let daemon = iface.get().await;
+    daemon
+        .lease_expirations_changed(iface.signal_context())
+        .await
+}
+
+/// checks that the containers `lifetime` is made of exist
+async fn check_containers(lifetime: &Lifetime) -> zbus::fdo::Result<()> {
+    for leaf in lifetime.leaves() {
+        let Lifetime::Container { engine, container } = leaf else {
+            continue;
+        };
+        match engine.container_exists(container).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(zbus::fdo::Error::InvalidArgs(format!(
+                    "{} has no container {}",
+                    engine, container
+                )))
+            }
+            Err(e) => {
+                return Err(zbus::fdo::Error::IOError(format!(
+                    "could not ask {} about container {}: {}",
+                    engine, container, e
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Daemon {
+    /// runs `f` with the entry manager locked, logging the error it fails with. mime databases
+    /// `f` changed are updated once the manager is unlocked again
+    async fn with_entry_manager<T>(
+        &self,
+        f: impl FnOnce(&mut EntryManager) -> Result<T, EntryManagerError>,
+    ) -> zbus::fdo::Result<T> {
+        let result = f(&mut *self.entry_manager.lock().await);
+        update_mime_databases(&self.entry_manager).await;
+        result.map_err(|e| {
+            log::error!("{:?}", e);
+            e.into()
+        })
+    }
+}
+
 #[interface(name = "io.ryanabx.DesktopEntry")]
 impl Daemon {
     /// register a new desktop entry with the calling process' lifetime
@@ -37,31 +179,12 @@ impl Daemon {
         appid: String,
         entry: String,
     ) -> zbus::fdo::Result<()> {
-        let dbus_proxy = DBusProxy::builder(conn)
-            .cache_properties(CacheProperties::No)
-            .build()
-            .await
-            .unwrap();
-        let pid = dbus_proxy
-            .get_connection_credentials(BusName::Unique(hdr.sender().unwrap().to_owned()))
-            .await
-            .unwrap()
-            .process_id()
-            .unwrap();
+        let pid = caller_pid(&hdr, conn).await;
         log::debug!("appid: {:?}, PID: {:?}", appid, pid);
         let lifetime = Lifetime::from_pid(pid).unwrap();
-        match self
-            .entry_manager
-            .lock()
-            .await
-            .register_entry(&entry, &appid, lifetime)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
-        }
+        self.with_entry_manager(|manager| manager.register_entry(&entry, &appid, lifetime))
+            .await?;
+        Ok(())
     }
 
     /// register a new desktop entry with the session's lifetime
@@ -73,18 +196,9 @@ impl Daemon {
     ) -> zbus::fdo::Result<()> {
         log::debug!("appid: {:?}, session, owner: {}", appid, owner);
         let lifetime = Lifetime::Session(owner);
-        match self
-            .entry_manager
-            .lock()
-            .await
-            .register_entry(&entry, &appid, lifetime)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
-        }
+        self.with_entry_manager(|manager| manager.register_entry(&entry, &appid, lifetime))
+            .await?;
+        Ok(())
     }
 
     /// register a new persistent desktop entry
@@ -96,18 +210,26 @@ impl Daemon {
     ) -> zbus::fdo::Result<()> {
         log::debug!("appid: {:?}, persistent, owner: {}", appid, owner);
         let lifetime = Lifetime::Persistent(owner);
-        match self
-            .entry_manager
-            .lock()
-            .await
-            .register_entry(&entry, &appid, lifetime)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
-        }
+        self.with_entry_manager(|manager| manager.register_entry(&entry, &appid, lifetime))
+            .await?;
+        Ok(())
+    }
+
+    /// register a new desktop entry with `lifetime`, any lifetime written in ron, including
+    /// compositions of lifetimes. returns the problems found in the entry that weren't bad
+    /// enough to refuse it, like `ValidateEntry`
+    async fn new_entry_with_lifetime(
+        &mut self,
+        appid: String,
+        entry: String,
+        lifetime: String,
+    ) -> zbus::fdo::Result<Vec<(u32, String, String)>> {
+        log::debug!("appid: {:?}, lifetime: {}", appid, lifetime);
+        let lifetime = parse_lifetime(&lifetime).await?;
+        let diagnostics = self
+            .with_entry_manager(|manager| manager.register_entry(&entry, &appid, lifetime))
+            .await?;
+        Ok(diagnostic_tuples(diagnostics))
     }
 
     /// register a new icon entry with the calling process' lifetime
@@ -118,31 +240,27 @@ impl Daemon {
         name: String,
         data: &[u8],
     ) -> zbus::fdo::Result<()> {
-        let dbus_proxy = DBusProxy::builder(conn)
-            .cache_properties(CacheProperties::No)
-            .build()
-            .await
-            .unwrap();
-        let pid = dbus_proxy
-            .get_connection_credentials(BusName::Unique(hdr.sender().unwrap().to_owned()))
+        self.new_process_icon_with_context(hdr, conn, name, "apps".to_string(), data)
             .await
-            .unwrap()
-            .process_id()
-            .unwrap();
-        log::debug!("icon: {:?}, PID: {:?}", name, pid);
+    }
+
+    /// register a new icon in the icon theme `context` (i.e. `apps`, `mimetypes`, `status`)
+    /// with the calling process' lifetime
+    async fn new_process_icon_with_context(
+        &mut self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        name: String,
+        context: String,
+        data: &[u8],
+    ) -> zbus::fdo::Result<()> {
+        let context = IconContext::from_str(&context)
+            .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string()))?;
+        let pid = caller_pid(&hdr, conn).await;
+        log::debug!("icon: {:?}, context: {:?}, PID: {:?}", name, context, pid);
         let lifetime = Lifetime::from_pid(pid).unwrap();
-        match self
-            .entry_manager
-            .lock()
+        self.with_entry_manager(|manager| manager.register_icon(&name, data, context, lifetime))
             .await
-            .register_icon(&name, &data, lifetime)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
-        }
     }
 
     /// register a new icon entry with the session's lifetime
@@ -152,20 +270,29 @@ impl Daemon {
         data: &[u8],
         owner: String,
     ) -> zbus::fdo::Result<()> {
-        log::debug!("icon: {:?}, session, owner: {}", name, owner);
+        self.new_session_icon_with_context(name, "apps".to_string(), data, owner)
+            .await
+    }
+
+    /// register a new icon in the icon theme `context` with the session's lifetime
+    async fn new_session_icon_with_context(
+        &mut self,
+        name: String,
+        context: String,
+        data: &[u8],
+        owner: String,
+    ) -> zbus::fdo::Result<()> {
+        let context = IconContext::from_str(&context)
+            .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string()))?;
+        log::debug!(
+            "icon: {:?}, context: {:?}, session, owner: {}",
+            name,
+            context,
+            owner
+        );
         let lifetime = Lifetime::Session(owner);
-        match self
-            .entry_manager
-            .lock()
+        self.with_entry_manager(|manager| manager.register_icon(&name, data, context, lifetime))
             .await
-            .register_icon(&name, &data, lifetime)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
-        }
     }
 
     /// register a new persistent icon entry
@@ -175,63 +302,413 @@ impl Daemon {
         data: &[u8],
         owner: String,
     ) -> zbus::fdo::Result<()> {
-        log::debug!("icon: {:?}, persistent, owner: {}", name, owner);
+        self.new_persistent_icon_with_context(name, "apps".to_string(), data, owner)
+            .await
+    }
+
+    /// register a new persistent icon in the icon theme `context`
+    async fn new_persistent_icon_with_context(
+        &mut self,
+        name: String,
+        context: String,
+        data: &[u8],
+        owner: String,
+    ) -> zbus::fdo::Result<()> {
+        let context = IconContext::from_str(&context)
+            .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string()))?;
+        log::debug!(
+            "icon: {:?}, context: {:?}, persistent, owner: {}",
+            name,
+            context,
+            owner
+        );
         let lifetime = Lifetime::Persistent(owner);
-        match self
-            .entry_manager
-            .lock()
+        self.with_entry_manager(|manager| manager.register_icon(&name, data, context, lifetime))
             .await
-            .register_icon(&name, &data, lifetime)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
+    }
+
+    /// register a new icon in the icon theme `context` with `lifetime`, any lifetime written
+    /// in ron, including compositions of lifetimes
+    async fn new_icon_with_lifetime(
+        &mut self,
+        name: String,
+        context: String,
+        data: &[u8],
+        lifetime: String,
+    ) -> zbus::fdo::Result<()> {
+        let context = IconContext::from_str(&context)
+            .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string()))?;
+        log::debug!(
+            "icon: {:?}, context: {:?}, lifetime: {}",
+            name,
+            context,
+            lifetime
+        );
+        let lifetime = parse_lifetime(&lifetime).await?;
+        self.with_entry_manager(|manager| manager.register_icon(&name, data, context, lifetime))
+            .await
+    }
+
+    /// register a new shared-mime-info package with the calling process' lifetime
+    async fn new_process_mime_package(
+        &mut self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        name: String,
+        package: String,
+    ) -> zbus::fdo::Result<()> {
+        let pid = caller_pid(&hdr, conn).await;
+        log::debug!("mime package: {:?}, PID: {:?}", name, pid);
+        let lifetime = Lifetime::from_pid(pid).unwrap();
+        self.with_entry_manager(|manager| manager.register_mime_package(&package, &name, lifetime))
+            .await
+    }
+
+    /// register a new shared-mime-info package with the session's lifetime
+    async fn new_session_mime_package(
+        &mut self,
+        name: String,
+        package: String,
+        owner: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("mime package: {:?}, session, owner: {}", name, owner);
+        let lifetime = Lifetime::Session(owner);
+        self.with_entry_manager(|manager| manager.register_mime_package(&package, &name, lifetime))
+            .await
+    }
+
+    /// register a new persistent shared-mime-info package
+    async fn new_persistent_mime_package(
+        &mut self,
+        name: String,
+        package: String,
+        owner: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("mime package: {:?}, persistent, owner: {}", name, owner);
+        let lifetime = Lifetime::Persistent(owner);
+        self.with_entry_manager(|manager| manager.register_mime_package(&package, &name, lifetime))
+            .await
+    }
+
+    /// register a new shared-mime-info package with `lifetime`, any lifetime written in ron,
+    /// including compositions of lifetimes
+    async fn new_mime_package_with_lifetime(
+        &mut self,
+        name: String,
+        package: String,
+        lifetime: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("mime package: {:?}, lifetime: {}", name, lifetime);
+        let lifetime = parse_lifetime(&lifetime).await?;
+        self.with_entry_manager(|manager| manager.register_mime_package(&package, &name, lifetime))
+            .await
+    }
+
+    /// register a new autostart entry with the calling process' lifetime
+    async fn new_process_autostart(
+        &mut self,
+        #[zbus(header)] hdr: Header<'_>,
+        #[zbus(connection)] conn: &Connection,
+        appid: String,
+        entry: String,
+    ) -> zbus::fdo::Result<()> {
+        let pid = caller_pid(&hdr, conn).await;
+        log::debug!("autostart appid: {:?}, PID: {:?}", appid, pid);
+        let lifetime = Lifetime::from_pid(pid).unwrap();
+        self.with_entry_manager(|manager| manager.register_autostart(&entry, &appid, lifetime))
+            .await
+    }
+
+    /// register a new autostart entry with the session's lifetime
+    async fn new_session_autostart(
+        &mut self,
+        appid: String,
+        entry: String,
+        owner: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("autostart appid: {:?}, session, owner: {}", appid, owner);
+        let lifetime = Lifetime::Session(owner);
+        self.with_entry_manager(|manager| manager.register_autostart(&entry, &appid, lifetime))
+            .await
+    }
+
+    /// register a new persistent autostart entry. only allowed with a permissive exec policy
+    async fn new_persistent_autostart(
+        &mut self,
+        appid: String,
+        entry: String,
+        owner: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("autostart appid: {:?}, persistent, owner: {}", appid, owner);
+        let lifetime = Lifetime::Persistent(owner);
+        self.with_entry_manager(|manager| manager.register_autostart(&entry, &appid, lifetime))
+            .await
+    }
+
+    /// register a new autostart entry with `lifetime`, any lifetime written in ron, including
+    /// compositions of lifetimes. lifetimes that may outlive the session are only allowed with
+    /// a permissive exec policy
+    async fn new_autostart_with_lifetime(
+        &mut self,
+        appid: String,
+        entry: String,
+        lifetime: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("autostart appid: {:?}, lifetime: {}", appid, lifetime);
+        let lifetime = parse_lifetime(&lifetime).await?;
+        self.with_entry_manager(|manager| manager.register_autostart(&entry, &appid, lifetime))
+            .await
+    }
+
+    /// register a d-bus activation file for the registered entry `appid`, for entries with
+    /// `DBusActivatable=true`. the service shares the lifetime of the entry
+    async fn new_dbus_service(&mut self, appid: String, service: String) -> zbus::fdo::Result<()> {
+        log::debug!("d-bus service: {:?}", appid);
+        self.with_entry_manager(|manager| manager.register_dbus_service(&service, &appid))
+            .await
+    }
+
+    /// choose how entries from `owner` are saved when their appid is already installed.
+    /// `mode` is `prefix` (`<owner>.<appid>.desktop`), `suffix` (`<appid>.<owner>.desktop`),
+    /// or `reject` (the default, fails with a duplicate app id error). lifetimes without an
+    /// owner are namespaced by what they're bound to, i.e. the path of a file
+    async fn set_namespace_mode(&mut self, owner: String, mode: String) -> zbus::fdo::Result<()> {
+        let mode = NamespaceMode::from_str(&mode).map_err(zbus::fdo::Error::InvalidArgs)?;
+        log::debug!("namespace mode for {}: {:?}", owner, mode);
+        self.with_entry_manager(|manager| manager.set_namespace_mode(&owner, mode))
+            .await
+    }
+
+    /// returns the desktop file id the registered entry `appid` was saved under, which differs
+    /// from the appid if the entry was namespaced
+    async fn get_desktop_file_id(&self, appid: String) -> zbus::fdo::Result<String> {
+        match self.entry_manager.lock().await.entry_for_appid(&appid) {
+            Some((_, handle)) => Ok(handle.desktop_file_id()),
+            None => Err(EntryManagerError::UnknownAppID(appid).into()),
         }
     }
 
+    /// returns every installed app id the daemon knows about and the files it is installed
+    /// at, for diagnosing duplicate app id errors
+    async fn get_app_index(&self) -> HashMap<String, Vec<String>> {
+        self.entry_manager
+            .lock()
+            .await
+            .app_index
+            .apps()
+            .iter()
+            .map(|(appid, paths)| {
+                (
+                    appid.clone(),
+                    paths
+                        .iter()
+                        .map(|x| x.to_string_lossy().to_string())
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    /// checks `entry` against the desktop entry spec without registering it. returns every
+    /// problem found as (line, severity, message), where severity is "error" or "warning"
+    async fn validate_entry(&self, entry: String) -> Vec<(u32, String, String)> {
+        diagnostic_tuples(validation::validate(&entry))
+    }
+
+    /// make the registered entry `appid` the default application for `mime_type`. the
+    /// association is removed along with the entry's lifetime
+    async fn set_default_application(
+        &mut self,
+        mime_type: String,
+        appid: String,
+    ) -> zbus::fdo::Result<()> {
+        log::debug!("default application for {}: {}", mime_type, appid);
+        self.with_entry_manager(|manager| manager.set_default_application(&mime_type, &appid))
+            .await
+    }
+
+    /// returns the desktop file set as the default application for `mime_type` through the
+    /// daemon, or an empty string if there is none
+    async fn get_default_application(&self, mime_type: String) -> String {
+        self.entry_manager
+            .lock()
+            .await
+            .default_application(&mime_type)
+            .unwrap_or_default()
+    }
+
+    /// removes the default application set through the daemon for `mime_type`
+    async fn remove_default_application(&mut self, mime_type: String) -> zbus::fdo::Result<()> {
+        self.with_entry_manager(|manager| manager.remove_default_application(&mime_type))
+            .await
+    }
+
     /// removes all entries and/or icons owned by `owner` for the session lifetime
     async fn remove_session_owner(&mut self, owner: String) -> zbus::fdo::Result<()> {
         let lifetime = Lifetime::Session(owner);
-        let mut entry_lock = self.entry_manager.lock().await;
-        match entry_lock.remove_lifetime(lifetime) {
-            Ok(_) => {
-                if let Err(e) = entry_lock.save_cache() {
-                    return Err(e.into());
-                }
-                Ok(())
-            }
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
-            }
-        }
+        self.with_entry_manager(|manager| {
+            manager.remove_lifetime(lifetime)?;
+            manager.save_cache()
+        })
+        .await
     }
 
     /// removes all entries and/or icons owned by `owner` for the persistent lifetime
     async fn remove_persistent_owner(&mut self, owner: String) -> zbus::fdo::Result<()> {
         let lifetime = Lifetime::Persistent(owner);
-        let mut entry_lock = self.entry_manager.lock().await;
-        match entry_lock.remove_lifetime(lifetime) {
-            Ok(_) => {
-                if let Err(e) = entry_lock.save_cache() {
-                    return Err(e.into());
-                }
-                Ok(())
-            }
-            Err(e) => {
-                log::error!("{:?}", e);
-                Err(e.into())
+        self.with_entry_manager(|manager| {
+            manager.remove_lifetime(lifetime)?;
+            manager.save_cache()
+        })
+        .await
+    }
+
+    /// removes all entries and/or icons registered with `lifetime`, written in ron
+    async fn remove_lifetime(&mut self, lifetime: String) -> zbus::fdo::Result<()> {
+        let lifetime: Lifetime = ron::from_str(&lifetime)
+            .map_err(|e| EntryManagerError::InvalidLifetime(e.to_string()))?;
+        self.with_entry_manager(|manager| {
+            if !manager.cache.lifetimes().contains(&lifetime) {
+                return Err(EntryManagerError::UnknownOwner(format!("{:?}", lifetime)));
             }
-        }
+            manager.remove_lifetime(lifetime)?;
+            manager.save_cache()
+        })
+        .await
+    }
+
+    /// moves everything registered with `from` to `to`, both written in ron, along with their
+    /// files. i.e. an installer can register its entries with its process and keep them once
+    /// it succeeded. emits `LifetimeChanged` once everything has moved
+    async fn promote_lifetime(
+        &mut self,
+        from: String,
+        to: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let from: Lifetime =
+            ron::from_str(&from).map_err(|e| EntryManagerError::InvalidLifetime(e.to_string()))?;
+        let to = parse_lifetime(&to).await?;
+        let paths = self
+            .with_entry_manager(|manager| manager.promote_lifetime(&from, &to))
+            .await?;
+        notify_lifetime_changed(&ctxt, &from, &to, paths).await
     }
+
+    /// moves the registered entry `appid` to the lifetime `to`, written in ron, along with its
+    /// d-bus service and mime associations. emits `LifetimeChanged` once it has moved
+    async fn transfer_entry(
+        &mut self,
+        appid: String,
+        to: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let to = parse_lifetime(&to).await?;
+        let (from, paths) = self
+            .with_entry_manager(|manager| manager.transfer_entry(&appid, &to))
+            .await?;
+        notify_lifetime_changed(&ctxt, &from, &to, paths).await
+    }
+
+    /// moves the deadline of everything `owner` registered with a deadline to `deadline`, in
+    /// seconds since the unix epoch. the deadline has to be in the future, and can't be
+    /// earlier than the current one. use `RemoveLifetime` to remove the resources sooner
+    async fn extend_deadline(&mut self, owner: String, deadline: u64) -> zbus::fdo::Result<()> {
+        self.with_entry_manager(|manager| manager.extend_deadline(&owner, deadline))
+            .await
+    }
+
+    /// starts a lease for a client the daemon can't watch, i.e. one on another machine. the
+    /// lease expires `interval` seconds after it was last renewed, and its resources are
+    /// removed once a renewal is more than `grace` seconds late. returns the lease's id, to
+    /// register resources with, and the token to renew it with, which only the client should
+    /// know
+    async fn new_lease(
+        &mut self,
+        interval: u64,
+        grace: u64,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> zbus::fdo::Result<(String, String)> {
+        let lease = self
+            .with_entry_manager(|manager| manager.new_lease(interval, grace))
+            .await?;
+        self.lease_expirations_changed(&ctxt).await?;
+        Ok(lease)
+    }
+
+    /// renews the lease with `token` for another interval. returns when it expires now, in
+    /// seconds since the unix epoch
+    async fn renew(
+        &mut self,
+        token: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> zbus::fdo::Result<u64> {
+        let expires = self
+            .with_entry_manager(|manager| manager.renew_lease(&token))
+            .await?;
+        self.lease_expirations_changed(&ctxt).await?;
+        Ok(expires)
+    }
+
+    /// ends the lease with `token` and removes everything that ends with it, without waiting
+    /// for it to lapse
+    async fn release_lease(
+        &mut self,
+        token: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> zbus::fdo::Result<()> {
+        self.with_entry_manager(|manager| manager.release_lease(&token))
+            .await?;
+        Ok(self.lease_expirations_changed(&ctxt).await?)
+    }
+
+    /// rebuilds the daemon's record of registered resources from the files in the lifetime
+    /// directories, for when it has lost track of them. returns how many were found
+    async fn rebuild(&mut self) -> zbus::fdo::Result<u32> {
+        let count = self
+            .with_entry_manager(|manager| manager.rebuild_cache())
+            .await?;
+        Ok(count as u32)
+    }
+
+    /// lifetime directories that were missing from the session's `XDG_DATA_DIRS` when the
+    /// daemon started. resources registered in them won't be visible to the session unless
+    /// the symlink fallback is turned on
+    #[zbus(property)]
+    async fn missing_data_dirs(&self) -> Vec<String> {
+        self.missing_data_dirs.clone()
+    }
+
+    /// when each lease expires unless it's renewed, in seconds since the unix epoch, by id.
+    /// resources of a lease are removed once it has been expired for longer than its grace
+    /// period
+    #[zbus(property)]
+    async fn lease_expirations(&self) -> HashMap<String, u64> {
+        self.entry_manager.lock().await.lease_expirations()
+    }
+
+    /// signal for when the daemon starts and finds lifetime directories missing from the
+    /// session's `XDG_DATA_DIRS`
+    #[zbus(signal)]
+    pub async fn data_dirs_missing(ctxt: &SignalContext<'_>, dirs: Vec<String>)
+        -> zbus::Result<()>;
+
+    /// signal for when resources were moved from the lifetime `old_lifetime` to
+    /// `new_lifetime`, both written in ron. `paths` are where the moved files are now
+    #[zbus(signal)]
+    pub async fn lifetime_changed(
+        ctxt: &SignalContext<'_>,
+        old_lifetime: &str,
+        new_lifetime: &str,
+        paths: Vec<String>,
+    ) -> zbus::Result<()>;
 }
 
 #[derive(Debug, Clone)]
 pub enum ValidationError {
     DuplicateAppID,
     NotValid(String),
+    Diagnostics(Vec<Diagnostic>),
 }
 
 impl Display for ValidationError {
@@ -243,6 +720,13 @@ impl Display for ValidationError {
             ValidationError::NotValid(reason) => {
                 write!(f, "Desktop entry failed validation: {}", reason)
             }
+            ValidationError::Diagnostics(diagnostics) => {
+                write!(f, "Desktop entry failed validation:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}", diagnostic)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,277 @@
+use std::fmt::Display;
+
+use crate::validation;
+
+/// a desktop entry file parsed into groups and keys, keeping the line each came from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DesktopFile {
+    /// comment lines, and lines that couldn't be parsed, before the first group
+    pub comments: Vec<String>,
+    pub groups: Vec<Group>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub line: usize,
+    /// comment lines, and lines that couldn't be parsed, directly above the group header
+    pub comments: Vec<String>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub locale: Option<String>,
+    pub value: String,
+    pub line: usize,
+    /// comment lines, and lines that couldn't be parsed, directly above the entry
+    pub comments: Vec<String>,
+}
+
+/// a line of a desktop entry that could not be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl DesktopFile {
+    /// parses `input` line by line. lines that can't be parsed are reported, and kept verbatim
+    /// like comments, so the rest of the file can still be looked at and writing the file
+    /// back doesn't lose them
+    pub fn parse(input: &str) -> (Self, Vec<ParseError>) {
+        let mut file = Self::default();
+        let mut errors = Vec::new();
+        let mut comments = Vec::new();
+        let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+        for (i, line) in input.lines().enumerate() {
+            let line_number = i + 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                if !trimmed.is_empty() {
+                    comments.push(trimmed.to_string());
+                }
+                continue;
+            }
+            if let Some(name) = trimmed.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                file.groups.push(Group {
+                    name: name.to_string(),
+                    line: line_number,
+                    comments: std::mem::take(&mut comments),
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+            let Some(group) = file.groups.last_mut() else {
+                errors.push(ParseError {
+                    line: line_number,
+                    message: "key outside of a group".to_string(),
+                });
+                comments.push(trimmed.to_string());
+                continue;
+            };
+            let Some((key, value)) = trimmed.split_once('=') else {
+                errors.push(ParseError {
+                    line: line_number,
+                    message: format!("'{}' is not a group header, key, or comment", trimmed),
+                });
+                comments.push(trimmed.to_string());
+                continue;
+            };
+            let key = key.trim_end();
+            let (key, locale) = match key.split_once('[') {
+                Some((key, locale)) => match locale.strip_suffix(']') {
+                    Some(locale) => (key, Some(locale.to_string())),
+                    None => {
+                        errors.push(ParseError {
+                            line: line_number,
+                            message: format!("'{}' has an unterminated locale", key),
+                        });
+                        comments.push(trimmed.to_string());
+                        continue;
+                    }
+                },
+                None => (key, None),
+            };
+            group.entries.push(Entry {
+                key: key.to_string(),
+                locale,
+                value: value.trim_start().to_string(),
+                line: line_number,
+                comments: std::mem::take(&mut comments),
+            });
+        }
+        if file.groups.is_empty() {
+            file.comments = comments;
+        } else if !comments.is_empty() {
+            // trailing comments stay at the end of the last group
+            let group = file.groups.last_mut().unwrap();
+            match group.entries.last_mut() {
+                Some(entry) => entry.comments.extend(comments),
+                None => group.comments.extend(comments),
+            }
+        }
+        if let Some(first) = file.groups.first_mut() {
+            file.comments = std::mem::take(&mut first.comments);
+        }
+        (file, errors)
+    }
+
+    /// the group called `name`, if there is one
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|x| x.name == name)
+    }
+
+    /// puts the file in canonical form: groups defined more than once are merged, a key set
+    /// more than once keeps its last value, and keys are sorted with the registered keys in
+    /// spec order first, each followed by its localized values. comments move with the
+    /// group or key below them
+    pub fn normalize(&mut self) {
+        let mut groups: Vec<Group> = Vec::new();
+        for group in std::mem::take(&mut self.groups) {
+            match groups.iter_mut().find(|x| x.name == group.name) {
+                Some(existing) => {
+                    existing.comments.extend(group.comments);
+                    existing.entries.extend(group.entries);
+                }
+                None => groups.push(group),
+            }
+        }
+        for group in &mut groups {
+            let mut entries: Vec<Entry> = Vec::new();
+            for entry in std::mem::take(&mut group.entries) {
+                match entries
+                    .iter()
+                    .position(|x| x.key == entry.key && x.locale == entry.locale)
+                {
+                    Some(i) => {
+                        let mut old = entries.remove(i);
+                        old.comments.extend(entry.comments);
+                        entries.push(Entry {
+                            comments: old.comments,
+                            ..entry
+                        });
+                    }
+                    None => entries.push(entry),
+                }
+            }
+            entries.sort_by(|a, b| {
+                let rank =
+                    |x: &Entry| validation::key_rank(&group.name, &x.key).unwrap_or(usize::MAX);
+                rank(a)
+                    .cmp(&rank(b))
+                    .then_with(|| a.key.cmp(&b.key))
+                    .then_with(|| a.locale.cmp(&b.locale))
+            });
+            group.entries = entries;
+        }
+        self.groups = groups;
+    }
+
+    /// sets `key` to `value` in the group called `group`, adding the key if it isn't set. a key
+    /// set more than once has its last value changed, the one `normalize` keeps
+    pub fn set(&mut self, group: &str, key: &str, value: &str) {
+        let Some(group) = self.groups.iter_mut().find(|x| x.name == group) else {
+            return;
+        };
+        match group
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|x| x.key == key && x.locale.is_none())
+        {
+            Some(entry) => entry.value = value.to_string(),
+            None => group.entries.push(Entry {
+                key: key.to_string(),
+                locale: None,
+                value: value.to_string(),
+                line: 0,
+                comments: Vec::new(),
+            }),
+        }
+    }
+
+    /// removes the unlocalized `key` from the group called `group`, if it is set
+    pub fn remove(&mut self, group: &str, key: &str) {
+        if let Some(group) = self.groups.iter_mut().find(|x| x.name == group) {
+            group.entries.retain(|x| x.key != key || x.locale.is_some());
+        }
+    }
+}
+
+/// escapes `value` for a string value as the desktop entry spec requires, so it can't end the
+/// line it's written on or start a key or group of its own
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// reverses `escape`, and the `\s` escape the spec defines for spaces
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('s') => unescaped.push(' '),
+            Some(x) => unescaped.push(x),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// writes the file with LF line endings and a blank line between groups
+impl Display for DesktopFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for comment in &self.comments {
+            writeln!(f, "{}", comment)?;
+        }
+        for (i, group) in self.groups.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            for comment in &group.comments {
+                writeln!(f, "{}", comment)?;
+            }
+            writeln!(f, "[{}]", group.name)?;
+            for entry in &group.entries {
+                for comment in &entry.comments {
+                    writeln!(f, "{}", comment)?;
+                }
+                match &entry.locale {
+                    Some(locale) => writeln!(f, "{}[{}]={}", entry.key, locale, entry.value)?,
+                    None => writeln!(f, "{}={}", entry.key, entry.value)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Group {
+    /// the unlocalized value of `key` in this group, if it is set. a key set more than once
+    /// has its last value, the one `DesktopFile::normalize` keeps
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|x| x.key == key && x.locale.is_none())
+    }
+}
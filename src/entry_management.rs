@@ -3,22 +3,51 @@ use std::{
     fmt::Display,
     fs::{self, create_dir_all},
     hash::Hash,
+    os::unix::fs::symlink,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
+use async_std::{sync::Mutex, task};
+use freedesktop_desktop_entry::default_paths;
 use image::{DynamicImage, ImageError};
 use ron::de::SpannedError;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::{daemon::ValidationError, tools::validate_desktop_entry};
+use crate::{
+    app_index::AppIndex,
+    config::{Config, DuplicateAction, ExecPolicy},
+    containers::{valid_container, ContainerEngine},
+    daemon::ValidationError,
+    storage::{self, Change, Record, Storage},
+    tools::{
+        claims, provenance, recorded_appid, unix_now, update_mime_database,
+        validate_autostart_entry, validate_dbus_service, validate_desktop_entry,
+        validate_mime_package, with_claims, with_lifetime, with_provenance, with_startup_wm_class,
+    },
+    validation::Diagnostic,
+};
 
 #[derive(Debug)]
 pub enum EntryManagerError {
     IO(std::io::Error),
     EntryValidation(ValidationError),
     IconValidation(IconValidationError),
+    MimeValidation(String),
     PathCollision(PathBuf),
+    UnknownAppID(String),
+    ServiceValidation(String),
+    PolicyDenied(String),
+    UnknownOwner(String),
+    InvalidPath(PathBuf),
+    InvalidContainer(String),
+    InvalidLifetime(String),
+    UnknownLease(String),
+    NotRegistered(PathBuf),
     Ron(ron::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
 }
 
 #[derive(Debug)]
@@ -26,13 +55,14 @@ pub enum IconValidationError {
     ImageFormat(ImageError),
     NotSquare,
     NoTypeFound,
+    UnknownContext(String),
 }
 
 impl Display for IconValidationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             IconValidationError::ImageFormat(e) => {
-                write!(f, "{}", e.to_string())
+                write!(f, "{}", e)
             }
             IconValidationError::NoTypeFound => {
                 write!(
@@ -43,6 +73,13 @@ impl Display for IconValidationError {
             IconValidationError::NotSquare => {
                 write!(f, "Icon is not square!")
             }
+            IconValidationError::UnknownContext(context) => {
+                write!(
+                    f,
+                    "Icon context '{}' is not a known icon theme context.",
+                    context
+                )
+            }
         }
     }
 }
@@ -71,44 +108,611 @@ impl From<ron::Error> for EntryManagerError {
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for EntryManagerError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub enum Lifetime {
     Process(u32),
     Session(String),
     Persistent(String),
+    /// lasts until `deadline`, in seconds since the unix epoch, across restarts
+    Expiring {
+        owner: String,
+        deadline: u64,
+    },
+    /// lasts while the systemd user unit with this name is active
+    Unit(String),
+    /// lasts while a file exists at `path`, following it when it's renamed within its
+    /// directory unless `remove_on_move` is set
+    Path {
+        path: PathBuf,
+        remove_on_move: bool,
+    },
+    /// lasts until the container with the name or id `container` is deleted from `engine`
+    Container {
+        engine: ContainerEngine,
+        container: String,
+    },
+    /// lasts while the client keeps renewing the lease with this id. the id is public, the
+    /// lease is renewed with the token it's the hash of
+    Lease(String),
+    /// ends as soon as any of these lifetimes ends
+    AnyOf(Vec<Lifetime>),
+    /// ends once all of these lifetimes have ended
+    AllOf(Vec<Lifetime>),
+}
+
+/// how long the files of a lifetime may have to be kept, which decides the directory they're
+/// written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Process,
+    Session,
+    Persistent,
 }
 
 impl Lifetime {
     pub fn from_pid(pid: u32) -> Result<Self, ()> {
         Ok(Lifetime::Process(pid))
     }
+
+    /// a lifetime lasting while the file at the absolute `path` exists
+    pub fn from_path(path: PathBuf, remove_on_move: bool) -> Result<Self, EntryManagerError> {
+        if !path.is_absolute() || path.symlink_metadata().is_err() {
+            return Err(EntryManagerError::InvalidPath(path));
+        }
+        Ok(Lifetime::Path {
+            path,
+            remove_on_move,
+        })
+    }
+
+    /// a lifetime lasting until `container` is deleted from the container engine `engine`
+    pub fn from_container(engine: &str, container: String) -> Result<Self, EntryManagerError> {
+        let engine =
+            ContainerEngine::from_str(engine).map_err(EntryManagerError::InvalidContainer)?;
+        if !valid_container(&container) {
+            return Err(EntryManagerError::InvalidContainer(format!(
+                "{} is not a container name or id",
+                container
+            )));
+        }
+        Ok(Lifetime::Container { engine, container })
+    }
+
+    /// the owner string a client registered this lifetime with, if it has one. a composition
+    /// has the owner of the first of its lifetimes that has one
+    pub fn owner(&self) -> Option<&str> {
+        match self {
+            Lifetime::Process(_)
+            | Lifetime::Path { .. }
+            | Lifetime::Container { .. }
+            | Lifetime::Lease(_) => None,
+            Lifetime::Session(owner)
+            | Lifetime::Persistent(owner)
+            | Lifetime::Expiring { owner, .. }
+            | Lifetime::Unit(owner) => Some(owner),
+            Lifetime::AnyOf(lifetimes) | Lifetime::AllOf(lifetimes) => {
+                lifetimes.iter().find_map(|x| x.owner())
+            }
+        }
+    }
+
+    /// the name entries with this lifetime are namespaced by, which its namespace mode is set
+    /// for. that's the owner if it has one, and otherwise what it's bound to: the executable
+    /// of a process, or its pid if that can't be read, the path of a file, or the id of a
+    /// container or lease. a composition has the namespace of its first lifetime
+    pub fn namespace(&self) -> String {
+        if let Some(owner) = self.owner() {
+            return owner.to_string();
+        }
+        match self {
+            Lifetime::Process(pid) => fs::read_link(format!("/proc/{}/exe", pid))
+                .ok()
+                .and_then(|x| Some(x.file_name()?.to_string_lossy().to_string()))
+                .unwrap_or_else(|| pid.to_string()),
+            Lifetime::Path { path, .. } => path.to_string_lossy().to_string(),
+            Lifetime::Container { container, .. } => container.clone(),
+            Lifetime::Lease(id) => id.clone(),
+            Lifetime::AnyOf(lifetimes) | Lifetime::AllOf(lifetimes) => {
+                lifetimes.first().map(|x| x.namespace()).unwrap_or_default()
+            }
+            Lifetime::Session(owner)
+            | Lifetime::Persistent(owner)
+            | Lifetime::Expiring { owner, .. }
+            | Lifetime::Unit(owner) => owner.clone(),
+        }
+    }
+
+    /// how long resources with this lifetime may have to be kept. a composition that ends
+    /// with any of its lifetimes has the shortest scope among them, and one that ends with
+    /// all of them has the longest
+    pub fn scope(&self) -> Scope {
+        match self {
+            Lifetime::Process(_) => Scope::Process,
+            Lifetime::Session(_) | Lifetime::Unit(_) => Scope::Session,
+            Lifetime::Persistent(_)
+            | Lifetime::Expiring { .. }
+            | Lifetime::Path { .. }
+            | Lifetime::Container { .. }
+            | Lifetime::Lease(_) => Scope::Persistent,
+            Lifetime::AnyOf(lifetimes) => lifetimes
+                .iter()
+                .map(|x| x.scope())
+                .min()
+                .unwrap_or(Scope::Process),
+            Lifetime::AllOf(lifetimes) => lifetimes
+                .iter()
+                .map(|x| x.scope())
+                .max()
+                .unwrap_or(Scope::Process),
+        }
+    }
+
+    /// whether resources with this lifetime may still be around after the session ends
+    pub fn outlives_session(&self) -> bool {
+        self.scope() == Scope::Persistent
+    }
+
+    /// the lifetimes this one is composed of, or itself if it isn't a composition
+    pub fn leaves(&self) -> Vec<&Lifetime> {
+        match self {
+            Lifetime::AnyOf(lifetimes) | Lifetime::AllOf(lifetimes) => {
+                lifetimes.iter().flat_map(|x| x.leaves()).collect()
+            }
+            _ => vec![self],
+        }
+    }
+
+    /// whether this lifetime has ended, given the lifetimes that aren't compositions that
+    /// have
+    pub fn ended(&self, ended: &HashSet<Lifetime>) -> bool {
+        match self {
+            Lifetime::AnyOf(lifetimes) => lifetimes.iter().any(|x| x.ended(ended)),
+            Lifetime::AllOf(lifetimes) => lifetimes.iter().all(|x| x.ended(ended)),
+            _ => ended.contains(self),
+        }
+    }
+
+    /// this lifetime with each lifetime it's composed of replaced by `f` of it
+    pub fn map_leaves(&self, f: &impl Fn(&Lifetime) -> Lifetime) -> Lifetime {
+        match self {
+            Lifetime::AnyOf(lifetimes) => {
+                Lifetime::AnyOf(lifetimes.iter().map(|x| x.map_leaves(f)).collect())
+            }
+            Lifetime::AllOf(lifetimes) => {
+                Lifetime::AllOf(lifetimes.iter().map(|x| x.map_leaves(f)).collect())
+            }
+            _ => f(self),
+        }
+    }
+
+    /// checks a lifetime sent by a client: compositions can't be empty, and paths and
+    /// containers have to be valid
+    pub fn check(&self) -> Result<(), EntryManagerError> {
+        match self {
+            Lifetime::AnyOf(lifetimes) | Lifetime::AllOf(lifetimes) => {
+                if lifetimes.is_empty() {
+                    return Err(EntryManagerError::InvalidLifetime(
+                        "compositions need at least one lifetime".to_string(),
+                    ));
+                }
+                lifetimes.iter().try_for_each(|x| x.check())
+            }
+            Lifetime::Path {
+                path,
+                remove_on_move,
+            } => Lifetime::from_path(path.clone(), *remove_on_move).map(|_| ()),
+            Lifetime::Container { engine, container } => {
+                Lifetime::from_container(&engine.to_string(), container.clone()).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// whether this lifetime has a deadline that has passed at `now`, in seconds since the
+    /// unix epoch
+    pub fn expired(&self, now: u64) -> bool {
+        matches!(self, Lifetime::Expiring { deadline, .. } if *deadline <= now)
+    }
+}
+
+/// a lease a client keeps its resources alive with by renewing it, for clients the daemon
+/// can't watch otherwise, i.e. ones on another machine
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// how long each renewal lasts, in seconds
+    pub interval: u64,
+    /// how late a renewal may be before the lease lapses, in seconds
+    pub grace: u64,
+    /// when the lease expires unless it's renewed, in seconds since the unix epoch
+    pub expires: u64,
+}
+
+impl Lease {
+    /// the id of the lease renewed with `token`. only the client knows the token, the cache,
+    /// the lifetimes and everything the daemon publishes only ever see its hash
+    pub fn id(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// whether the lease went unrenewed for longer than its grace period at `now`
+    pub fn lapsed(&self, now: u64) -> bool {
+        now > self.expires.saturating_add(self.grace)
+    }
+}
+
+/// where an installed copy of an appid being registered came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateSource {
+    SameOwner,
+    OtherOwner,
+    User,
+    System,
+}
+
+/// what to do when an owner registers an entry whose appid is already installed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NamespaceMode {
+    /// refuse the entry with a duplicate app id error
+    #[default]
+    Reject,
+    /// save the entry as `<owner>.<appid>.desktop`
+    Prefix,
+    /// save the entry as `<appid>.<owner>.desktop`
+    Suffix,
+}
+
+impl NamespaceMode {
+    /// the desktop file id `appid` is saved under for `owner`, or `None` if the mode rejects
+    /// duplicates
+    pub fn apply(&self, appid: &str, owner: &str) -> Option<String> {
+        let namespace = owner
+            .chars()
+            .map(|x| {
+                if x.is_ascii_alphanumeric() || x == '-' || x == '_' {
+                    x
+                } else {
+                    '-'
+                }
+            })
+            .collect::<String>();
+        // paths start with a separator, which shouldn't end up at the start of the file name
+        let namespace = namespace.trim_matches('-');
+        match self {
+            NamespaceMode::Reject => None,
+            NamespaceMode::Prefix => Some(format!("{}.{}", namespace, appid)),
+            NamespaceMode::Suffix => Some(format!("{}.{}", appid, namespace)),
+        }
+    }
+}
+
+impl FromStr for NamespaceMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(NamespaceMode::Reject),
+            "prefix" => Ok(NamespaceMode::Prefix),
+            "suffix" => Ok(NamespaceMode::Suffix),
+            _ => Err(format!(
+                "'{}' is not a namespace mode, expected prefix, suffix, or reject",
+                s
+            )),
+        }
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct EntryManager {
     pub cache: EntryCache,
     pub proc_dir: PathBuf,
     pub session_dir: PathBuf,
     pub persistent_dir: PathBuf,
-    pub config_file: PathBuf,
+    pub autostart_dir: PathBuf,
+    pub data_home: PathBuf,
+    storage: Box<dyn Storage>,
+    /// changes made to `cache` since it was last saved
+    pending: Vec<Change>,
+    pub config: Config,
+    pub app_index: AppIndex,
     pub change_handlers: HashSet<u32>,
+    /// set when a d-bus service file was added or removed, until the bus has been asked to
+    /// reload its configuration
+    pub bus_reload_pending: bool,
+    /// the mime directories whose packages changed, until `update-mime-database` has been run
+    /// on them by `update_mime_databases`
+    pub mime_refresh_pending: HashSet<PathBuf>,
+    /// whether entries and icons are also linked into `data_home`, for sessions where the
+    /// lifetime directories aren't part of `XDG_DATA_DIRS`
+    pub symlink_exports: bool,
+    /// lifetimes that aren't compositions that have ended, but are part of a composition
+    /// that hasn't
+    ended: HashSet<Lifetime>,
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// the schema version of the cache written by this version of the daemon. bump it whenever
+/// a change to `EntryCache` can't be read from older caches through `serde(default)`, and add
+/// a step for the old version to `migrate_cache`
+pub const CACHE_VERSION: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EntryCache {
-    pub entries: HashMap<Lifetime, Vec<DesktopHandle>>,
-    pub icons: HashMap<Lifetime, Vec<IconHandle>>,
+    /// the schema version the cache was written with. caches from before versioning have none
+    #[serde(default)]
+    pub version: u32,
+    pub entries: Vec<Claimed<DesktopHandle>>,
+    pub icons: Vec<Claimed<IconHandle>>,
+    #[serde(default)]
+    pub resources: Vec<Claimed<ResourceHandle>>,
+    #[serde(default)]
+    pub associations: Vec<Claimed<MimeAssociation>>,
+    /// how each owner wants entries with an already installed appid to be saved
+    #[serde(default)]
+    pub namespace_modes: HashMap<String, NamespaceMode>,
+    /// the logind session that session lifetimes belong to, if the daemon could tell
+    #[serde(default)]
+    pub session: Option<String>,
+    /// every lease that hasn't lapsed or been released, by id
+    #[serde(default)]
+    pub leases: HashMap<String, Lease>,
+    /// the last time the daemon was known to be running, in seconds since the unix epoch.
+    /// leases are extended by the time the daemon wasn't running when it starts
+    #[serde(default)]
+    pub alive_at: u64,
+}
+
+/// the layout of the cache at schema version 1, where every entry and icon was owned by a
+/// single lifetime
+#[derive(Serialize, Deserialize)]
+struct EntryCacheV1 {
+    #[serde(default)]
+    version: u32,
+    entries: HashMap<Lifetime, Vec<DesktopHandle>>,
+    icons: HashMap<Lifetime, Vec<IconHandle>>,
+    #[serde(default)]
+    resources: HashMap<Lifetime, Vec<ResourceHandle>>,
+    #[serde(default)]
+    associations: HashMap<Lifetime, Vec<MimeAssociation>>,
+    #[serde(default)]
+    namespace_modes: HashMap<String, NamespaceMode>,
+    #[serde(default)]
+    session: Option<String>,
 }
 
+/// the layout of the cache at schema version 2, where only entries and icons could be claimed
+/// by more than one lifetime
+#[derive(Serialize, Deserialize)]
+struct EntryCacheV2 {
+    version: u32,
+    entries: Vec<Claimed<DesktopHandle>>,
+    icons: Vec<Claimed<IconHandle>>,
+    #[serde(default)]
+    resources: HashMap<Lifetime, Vec<ResourceHandle>>,
+    #[serde(default)]
+    associations: HashMap<Lifetime, Vec<MimeAssociation>>,
+    #[serde(default)]
+    namespace_modes: HashMap<String, NamespaceMode>,
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    leases: HashMap<String, Lease>,
+    #[serde(default)]
+    alive_at: u64,
+}
+
+/// a registered file and every lifetime holding a claim on it, i.e. when several owners
+/// registered the same app. the file is kept in the directories of its most durable claim,
+/// and removed once the last claim is gone
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claimed<T> {
+    pub handle: T,
+    pub claims: Vec<Lifetime>,
+}
+
+impl<T> Claimed<T> {
+    pub fn new(handle: T, lifetime: Lifetime) -> Self {
+        Self {
+            handle,
+            claims: vec![lifetime],
+        }
+    }
+
+    /// the claim the file is kept for, which decides the directories it's kept in
+    pub fn lifetime(&self) -> &Lifetime {
+        primary_claim(&self.claims)
+    }
+}
+
+/// the first of the most durable `claims`, which can't be empty
+fn primary_claim(claims: &[Lifetime]) -> &Lifetime {
+    claims.iter().rev().max_by_key(|x| x.scope()).unwrap()
+}
+
+/// `claims` with the claim `old` replaced by `new`, which is only kept once
+fn replace_claim(claims: &[Lifetime], old: &Lifetime, new: &Lifetime) -> Vec<Lifetime> {
+    let mut replaced = Vec::new();
+    for claim in claims {
+        let claim = if claim == old { new } else { claim };
+        if !replaced.contains(claim) {
+            replaced.push(claim.clone());
+        }
+    }
+    replaced
+}
+
+/// adds a claim by `lifetime` on `handle`, which is tracked in `claimed` if it isn't already
+pub fn add_claim<T: PartialEq>(claimed: &mut Vec<Claimed<T>>, lifetime: Lifetime, handle: T) {
+    match claimed.iter_mut().find(|x| x.handle == handle) {
+        Some(x) if !x.claims.contains(&lifetime) => x.claims.push(lifetime),
+        Some(_) => {}
+        None => claimed.push(Claimed::new(handle, lifetime)),
+    }
+}
+
+/// the icon theme spec context of an icon, which decides the `hicolor/<size>/<context>/`
+/// directory the icon is written into
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum IconContext {
+    Actions,
+    Animations,
+    #[default]
+    Apps,
+    Categories,
+    Devices,
+    Emblems,
+    Emotes,
+    International,
+    MimeTypes,
+    Places,
+    Status,
+}
+
+impl IconContext {
+    /// name of the context directory inside a theme size directory
+    pub fn directory(&self) -> &'static str {
+        match self {
+            IconContext::Actions => "actions",
+            IconContext::Animations => "animations",
+            IconContext::Apps => "apps",
+            IconContext::Categories => "categories",
+            IconContext::Devices => "devices",
+            IconContext::Emblems => "emblems",
+            IconContext::Emotes => "emotes",
+            IconContext::International => "intl",
+            IconContext::MimeTypes => "mimetypes",
+            IconContext::Places => "places",
+            IconContext::Status => "status",
+        }
+    }
+
+    /// value of the `Context` key for this context in `index.theme`
+    pub fn theme_context(&self) -> &'static str {
+        match self {
+            IconContext::Actions => "Actions",
+            IconContext::Animations => "Animations",
+            IconContext::Apps => "Applications",
+            IconContext::Categories => "Categories",
+            IconContext::Devices => "Devices",
+            IconContext::Emblems => "Emblems",
+            IconContext::Emotes => "Emotes",
+            IconContext::International => "International",
+            IconContext::MimeTypes => "MimeTypes",
+            IconContext::Places => "Places",
+            IconContext::Status => "Status",
+        }
+    }
+}
+
+impl FromStr for IconContext {
+    type Err = IconValidationError;
+
+    /// accepts either the directory name (`mimetypes`) or the `index.theme` context name
+    /// (`MimeTypes`), case insensitively
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "actions" => Ok(IconContext::Actions),
+            "animations" => Ok(IconContext::Animations),
+            "apps" | "applications" => Ok(IconContext::Apps),
+            "categories" => Ok(IconContext::Categories),
+            "devices" => Ok(IconContext::Devices),
+            "emblems" => Ok(IconContext::Emblems),
+            "emotes" => Ok(IconContext::Emotes),
+            "intl" | "international" => Ok(IconContext::International),
+            "mimetypes" => Ok(IconContext::MimeTypes),
+            "places" => Ok(IconContext::Places),
+            "status" => Ok(IconContext::Status),
+            _ => Err(IconValidationError::UnknownContext(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub enum ResourceType {
     DesktopEntry,
     Icon,
+    MimePackage,
+    Autostart,
+    DBusService,
+}
+
+impl Default for EntryCache {
+    fn default() -> Self {
+        Self {
+            version: CACHE_VERSION,
+            entries: Vec::new(),
+            icons: Vec::new(),
+            resources: Vec::new(),
+            associations: Vec::new(),
+            namespace_modes: HashMap::new(),
+            session: None,
+            leases: HashMap::new(),
+            alive_at: 0,
+        }
+    }
+}
+
+/// just the schema version of a cache, to decide how to read the rest of it
+#[derive(Deserialize)]
+struct CacheVersion {
+    #[serde(default)]
+    version: u32,
 }
 
+#[derive(Debug)]
 pub enum ConfigError {
     IO(std::io::Error),
     Parse(SpannedError),
+    Serialize(ron::Error),
+    /// the cache was written with this schema version, which the daemon can't read, i.e. by
+    /// a newer daemon
+    UnsupportedVersion(u32),
+    /// the cache couldn't be migrated from this schema version to the next
+    Migration(u32, Box<ConfigError>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    /// the ron cache couldn't be imported into another storage backend
+    #[cfg(feature = "sqlite")]
+    Import(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::IO(e) => {
+                write!(f, "{}", e)
+            }
+            ConfigError::Parse(e) => {
+                write!(f, "{}", e)
+            }
+            ConfigError::Serialize(e) => {
+                write!(f, "{}", e)
+            }
+            ConfigError::UnsupportedVersion(version) => {
+                write!(f, "cache schema version {} is not supported", version)
+            }
+            ConfigError::Migration(version, e) => {
+                write!(
+                    f,
+                    "could not migrate the cache from schema version {}: {}",
+                    version, e
+                )
+            }
+            #[cfg(feature = "sqlite")]
+            ConfigError::Sqlite(e) => {
+                write!(f, "{}", e)
+            }
+            #[cfg(feature = "sqlite")]
+            ConfigError::Import(e) => {
+                write!(f, "could not import the ron cache: {}", e)
+            }
+        }
+    }
 }
 
 impl From<std::io::Error> for ConfigError {
@@ -123,12 +727,177 @@ impl From<SpannedError> for ConfigError {
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for ConfigError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value)
+    }
+}
+
+impl From<ron::Error> for ConfigError {
+    fn from(value: ron::Error) -> Self {
+        Self::Serialize(value)
+    }
+}
+
 impl EntryCache {
+    /// loads the cache at `config_dir`, migrating it first if it was written with an older
+    /// schema version. the old cache is backed up next to it before it is migrated
     pub fn new(config_dir: &Path) -> Result<Self, ConfigError> {
-        let str_data = fs::read_to_string(config_dir)?;
+        let mut str_data = fs::read_to_string(config_dir)?;
+        let CacheVersion { mut version } = ron::from_str(&str_data)?;
+        if version > CACHE_VERSION {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+        if version < CACHE_VERSION {
+            let backup = cache_backup_path(config_dir, &format!("v{}", version));
+            fs::copy(config_dir, &backup)?;
+            log::info!(
+                "Migrating the cache from schema version {} to {}, backed up the old one to {:?}",
+                version,
+                CACHE_VERSION,
+                backup
+            );
+            while version < CACHE_VERSION {
+                str_data = migrate_cache(version, &str_data)
+                    .map_err(|e| ConfigError::Migration(version, Box::new(e)))?;
+                version += 1;
+            }
+            fs::write(config_dir, &str_data)?;
+        }
         let cache: Self = ron::from_str(&str_data)?;
         Ok(cache)
     }
+
+    /// the paths of every registered file
+    pub fn paths(&self) -> HashSet<PathBuf> {
+        self.entries
+            .iter()
+            .map(|x| x.handle.path.clone())
+            .chain(self.icons.iter().map(|x| x.handle.icon_path.clone()))
+            .chain(self.resources.iter().map(|x| x.handle.path.clone()))
+            .collect()
+    }
+
+    /// every lifetime that isn't a composition that a lifetime owning resources is made of
+    pub fn leaves(&self) -> HashSet<Lifetime> {
+        self.lifetimes()
+            .iter()
+            .flat_map(|x| x.leaves())
+            .cloned()
+            .collect()
+    }
+
+    /// every lifetime that currently owns or claims at least one resource
+    pub fn lifetimes(&self) -> HashSet<Lifetime> {
+        self.entries
+            .iter()
+            .flat_map(|x| x.claims.iter())
+            .chain(self.icons.iter().flat_map(|x| x.claims.iter()))
+            .chain(self.resources.iter().flat_map(|x| x.claims.iter()))
+            .chain(self.associations.iter().flat_map(|x| x.claims.iter()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// rewrites a cache of schema `version` into the layout of the version after it. each step
+/// only needs to know the layouts of its two versions, so when `EntryCache` changes, the step
+/// reading the old layout keeps a private copy of it
+fn migrate_cache(version: u32, data: &str) -> Result<String, ConfigError> {
+    match version {
+        // version 0 is the layout from before versioning, which only lacks the version
+        0 => {
+            let mut cache: EntryCacheV1 = ron::from_str(data)?;
+            cache.version = 1;
+            Ok(ron::ser::to_string_pretty(
+                &cache,
+                ron::ser::PrettyConfig::default(),
+            )?)
+        }
+        // version 1 kept entries and icons under the single lifetime that owned them, which
+        // becomes their only claim
+        1 => {
+            let old: EntryCacheV1 = ron::from_str(data)?;
+            let mut cache = EntryCacheV2 {
+                version: 2,
+                entries: Vec::new(),
+                icons: Vec::new(),
+                resources: old.resources,
+                associations: old.associations,
+                namespace_modes: old.namespace_modes,
+                session: old.session,
+                leases: HashMap::new(),
+                alive_at: 0,
+            };
+            for (lifetime, handles) in old.entries {
+                for handle in handles {
+                    add_claim(&mut cache.entries, lifetime.clone(), handle);
+                }
+            }
+            for (lifetime, handles) in old.icons {
+                for handle in handles {
+                    add_claim(&mut cache.icons, lifetime.clone(), handle);
+                }
+            }
+            Ok(ron::ser::to_string_pretty(
+                &cache,
+                ron::ser::PrettyConfig::default(),
+            )?)
+        }
+        // version 2 kept resources and associations under the single lifetime that owned
+        // them, which becomes their only claim
+        2 => {
+            let old: EntryCacheV2 = ron::from_str(data)?;
+            let mut cache = EntryCache {
+                version: 3,
+                entries: old.entries,
+                icons: old.icons,
+                resources: Vec::new(),
+                associations: Vec::new(),
+                namespace_modes: old.namespace_modes,
+                session: old.session,
+                leases: old.leases,
+                alive_at: old.alive_at,
+            };
+            for (lifetime, handles) in old.resources {
+                for handle in handles {
+                    add_claim(&mut cache.resources, lifetime.clone(), handle);
+                }
+            }
+            for (lifetime, associations) in old.associations {
+                for association in associations {
+                    add_claim(&mut cache.associations, lifetime.clone(), association);
+                }
+            }
+            Ok(ron::ser::to_string_pretty(
+                &cache,
+                ron::ser::PrettyConfig::default(),
+            )?)
+        }
+        _ => Err(ConfigError::UnsupportedVersion(version)),
+    }
+}
+
+/// runs `update-mime-database` on the mime directories `manager` queued, without keeping it
+/// locked while it runs. directories queued again while it runs are only updated once more,
+/// after it's done
+pub async fn update_mime_databases(manager: &Mutex<EntryManager>) {
+    static RUNNING: Mutex<()> = Mutex::new(());
+    let _running = RUNNING.lock().await;
+    let dirs = std::mem::take(&mut manager.lock().await.mime_refresh_pending);
+    for dir in dirs {
+        if let Err(e) = task::spawn_blocking(move || update_mime_database(&dir)).await {
+            log::warn!("could not update the mime database: {:?}", e);
+        }
+    }
+}
+
+/// where the cache at `path` is backed up to, i.e. `cache.ron.v0.bak` for a `tag` of `v0`
+pub fn cache_backup_path(path: &Path, tag: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".{}.bak", tag));
+    path.with_file_name(file_name)
 }
 
 impl EntryManager {
@@ -136,126 +905,927 @@ impl EntryManager {
         proc_dir: PathBuf,
         session_dir: PathBuf,
         persistent_dir: PathBuf,
+        autostart_dir: PathBuf,
+        data_home: PathBuf,
         config_file: PathBuf,
+        config: Config,
     ) -> Self {
+        let mut storage = storage::open(config.storage, config_file);
+        let (cache, loaded) = match storage.load() {
+            Ok(cache) => (cache, true),
+            Err(ConfigError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!(
+                    "no cache at {:?}, rebuilding it from the lifetime directories",
+                    storage.path()
+                );
+                (EntryCache::default(), false)
+            }
+            Err(e) => {
+                // keep the unreadable cache around, since the rebuilt one replaces it
+                let tag = match e {
+                    ConfigError::UnsupportedVersion(version) => format!("v{}", version),
+                    _ => "unreadable".to_string(),
+                };
+                let backup = cache_backup_path(storage.path(), &tag);
+                if let Err(e) = fs::copy(storage.path(), &backup) {
+                    log::error!("could not back up the cache to {:?}: {}", backup, e);
+                }
+                log::warn!(
+                    "could not load the cache, backed it up to {:?} and rebuilding it from the lifetime directories: {}",
+                    backup,
+                    e
+                );
+                (EntryCache::default(), false)
+            }
+        };
         let mut manager = Self {
-            cache: EntryCache::new(&config_file).unwrap_or_default(),
+            cache,
             proc_dir,
             session_dir,
             persistent_dir,
-            config_file,
+            autostart_dir,
+            data_home,
+            storage,
+            pending: Vec::new(),
+            config,
+            app_index: AppIndex::new(default_paths()),
             change_handlers: HashSet::new(),
+            bus_reload_pending: false,
+            mime_refresh_pending: HashSet::new(),
+            symlink_exports: false,
+            ended: HashSet::new(),
         };
-        if let Err(e) = manager.reset_session() {
-            log::warn!(
-                "there was a problem resetting the session lifetime: {:?}",
-                e
-            );
+        if loaded {
+            manager.adopt_entries();
+        } else if let Err(e) = manager.rebuild_cache() {
+            log::warn!("there was a problem rebuilding the cache: {:?}", e);
+        }
+        if let Err(e) = manager.resume_leases(unix_now()) {
+            log::warn!("there was a problem resuming leases: {:?}", e);
         }
         manager
     }
+
+    /// ties session lifetimes to the logind session `session`. resources registered in
+    /// another session are removed, and so are all of them if there is no session to tie
+    /// them to, i.e. without logind or for a lingering user who isn't logged in. then they
+    /// end whenever the daemon starts, as they did before sessions were tracked
+    pub fn bind_session(&mut self, session: Option<String>) -> Result<(), EntryManagerError> {
+        if session.is_none() || self.cache.session != session {
+            log::info!(
+                "Binding session lifetimes to session {:?}, was {:?}",
+                session,
+                self.cache.session
+            );
+            self.reset_session()?;
+        }
+        self.set_session(session)
+    }
+
+    /// records that session lifetimes now belong to the logind session `session`, without
+    /// removing anything
+    pub fn set_session(&mut self, session: Option<String>) -> Result<(), EntryManagerError> {
+        if self.cache.session != session {
+            self.cache.session = session.clone();
+            self.pending.push(Change::Session(session));
+        }
+        self.save_cache()
+    }
+
+    /// re-adopts registered files the cache doesn't track, i.e. after the cache was lost or
+    /// couldn't be parsed. files whose lifetime has ended are then cleaned up like any other
+    fn adopt_entries(&mut self) {
+        let found = self.scan_lifetime_dirs();
+        let tracked = self.cache.paths();
+        let mut adopted = 0;
+        for claimed in found
+            .entries
+            .into_iter()
+            .filter(|x| !tracked.contains(&x.handle.path))
+        {
+            log::info!(
+                "Re-adopting {:?} claimed by {:?}",
+                claimed.handle.path,
+                claimed.claims
+            );
+            self.pending.extend(
+                claimed
+                    .claims
+                    .iter()
+                    .map(|x| Change::Insert(x.clone(), Record::Entry(claimed.handle.clone()))),
+            );
+            self.cache.entries.push(claimed);
+            adopted += 1;
+        }
+        for claimed in found
+            .icons
+            .into_iter()
+            .filter(|x| !tracked.contains(&x.handle.icon_path))
+        {
+            log::info!(
+                "Re-adopting {:?} claimed by {:?}",
+                claimed.handle.icon_path,
+                claimed.claims
+            );
+            self.pending.extend(
+                claimed
+                    .claims
+                    .iter()
+                    .map(|x| Change::Insert(x.clone(), Record::Icon(claimed.handle.clone()))),
+            );
+            self.cache.icons.push(claimed);
+            adopted += 1;
+        }
+        for claimed in found
+            .resources
+            .into_iter()
+            .filter(|x| !tracked.contains(&x.handle.path))
+        {
+            log::info!(
+                "Re-adopting {:?} claimed by {:?}",
+                claimed.handle.path,
+                claimed.claims
+            );
+            self.pending.extend(
+                claimed
+                    .claims
+                    .iter()
+                    .map(|x| Change::Insert(x.clone(), Record::Resource(claimed.handle.clone()))),
+            );
+            self.cache.resources.push(claimed);
+            adopted += 1;
+        }
+        for claimed in found.associations {
+            if self
+                .default_application(&claimed.handle.mime_type)
+                .is_some()
+            {
+                continue;
+            }
+            self.pending.extend(
+                claimed.claims.iter().map(|x| {
+                    Change::Insert(x.clone(), Record::Association(claimed.handle.clone()))
+                }),
+            );
+            self.cache.associations.push(claimed);
+            adopted += 1;
+        }
+        if adopted > 0 {
+            log::info!("Re-adopted {} resources missing from the cache", adopted);
+        }
+    }
+
+    /// replaces the cache with one rebuilt from the provenance keys and sidecar files in the
+    /// lifetime directories, returning how many resources were found. namespace modes and
+    /// leases aren't stored with any file, so they are kept from the current cache
+    pub fn rebuild_cache(&mut self) -> Result<usize, EntryManagerError> {
+        let mut cache = self.scan_lifetime_dirs();
+        cache.namespace_modes = std::mem::take(&mut self.cache.namespace_modes);
+        cache.session = self.cache.session.take();
+        cache.leases = std::mem::take(&mut self.cache.leases);
+        cache.alive_at = self.cache.alive_at;
+        self.cache = cache;
+        self.pending.push(Change::All);
+        let count = self.cache.paths().len() + self.cache.associations.len();
+        log::info!("Rebuilt the cache with {} resources", count);
+        self.save_cache()?;
+        Ok(count)
+    }
+
+    /// builds a cache from every registered file found in the lifetime directories. entries
+    /// and autostart entries are found by their provenance keys, everything else by the
+    /// `.provenance` sidecar file next to it
+    fn scan_lifetime_dirs(&self) -> EntryCache {
+        let mut cache = EntryCache::default();
+        for root in [&self.proc_dir, &self.session_dir, &self.persistent_dir] {
+            for path in walk(root) {
+                let Ok(relative) = path.strip_prefix(root) else {
+                    continue;
+                };
+                if relative.starts_with("applications")
+                    && path.extension().is_some_and(|x| x == "desktop")
+                {
+                    let Ok(entry) = fs::read_to_string(&path) else {
+                        continue;
+                    };
+                    let Some(claims) = claims(&entry) else {
+                        continue;
+                    };
+                    // namespaced entries are saved under another name than their app id.
+                    // entries written before the app id was recorded weren't
+                    let appid = recorded_appid(&entry)
+                        .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().to_string());
+                    let mut handle = DesktopHandle::new(&appid, path);
+                    handle.link = self.existing_link(&handle.path, root);
+                    cache.entries.push(Claimed { handle, claims });
+                    continue;
+                }
+                let Some(file) = path
+                    .to_str()
+                    .and_then(|x| x.strip_suffix(SIDECAR_EXTENSION))
+                    .map(PathBuf::from)
+                else {
+                    continue;
+                };
+                if file.file_name().is_some_and(|x| x == "mimeapps.list") {
+                    let Some(associations) = fs::read_to_string(&path).ok().and_then(|x| {
+                        ron::from_str::<HashMap<Lifetime, Vec<MimeAssociation>>>(&x).ok()
+                    }) else {
+                        continue;
+                    };
+                    // each association is listed under every lifetime claiming it
+                    for (lifetime, x) in associations {
+                        for association in x {
+                            add_claim(&mut cache.associations, lifetime.clone(), association);
+                        }
+                    }
+                    continue;
+                }
+                let Some(sidecar) = read_sidecar(&file) else {
+                    continue;
+                };
+                if relative.starts_with("icons") {
+                    let handle = IconHandle {
+                        icon_name: sidecar.name.clone(),
+                        link: self.existing_link(&file, root),
+                        icon_path: file,
+                    };
+                    cache.icons.push(Claimed {
+                        handle,
+                        claims: sidecar.claims(),
+                    });
+                    continue;
+                }
+                let resource_type = if relative.starts_with("mime/packages") {
+                    ResourceType::MimePackage
+                } else if relative.starts_with("dbus-1/services") {
+                    ResourceType::DBusService
+                } else {
+                    continue;
+                };
+                let handle = ResourceHandle {
+                    resource_type,
+                    name: sidecar.name.clone(),
+                    path: file,
+                };
+                cache.resources.push(Claimed {
+                    handle,
+                    claims: sidecar.claims(),
+                });
+            }
+        }
+        let autostart_dirs = [
+            self.autostart_dir.clone(),
+            self.session_dir.join("config/autostart"),
+            self.proc_dir.join("config/autostart"),
+        ];
+        for path in autostart_dirs.iter().flat_map(|x| walk(x)) {
+            if path.extension().is_none_or(|x| x != "desktop") {
+                continue;
+            }
+            let Ok(entry) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let Some(lifetime) = provenance(&entry) else {
+                continue;
+            };
+            let name = recorded_appid(&entry)
+                .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().to_string());
+            let handle = ResourceHandle {
+                resource_type: ResourceType::Autostart,
+                name,
+                path,
+            };
+            cache.resources.push(Claimed::new(handle, lifetime));
+        }
+        cache
+    }
+
+    /// the symlink exported for the file at `path` under the lifetime directory `root`, if it
+    /// still points there
+    fn existing_link(&self, path: &Path, root: &Path) -> Option<PathBuf> {
+        let link = self.data_home.join(path.strip_prefix(root).ok()?);
+        fs::read_link(&link)
+            .is_ok_and(|x| x == path)
+            .then_some(link)
+    }
+
     pub fn directory_for_lifetime(
         &self,
         lifetime: Lifetime,
         resource_type: ResourceType,
     ) -> PathBuf {
-        match (lifetime, resource_type) {
-            (Lifetime::Process(_), ResourceType::DesktopEntry) => {
-                self.proc_dir.join(Path::new("applications"))
-            }
-            (Lifetime::Process(_), ResourceType::Icon) => self.proc_dir.join(Path::new("icons")),
-            (Lifetime::Session(_), ResourceType::DesktopEntry) => {
-                self.session_dir.join(Path::new("applications"))
-            }
-            (Lifetime::Session(_), ResourceType::Icon) => self.session_dir.join(Path::new("icons")),
-            (Lifetime::Persistent(_), ResourceType::DesktopEntry) => {
-                self.persistent_dir.join(Path::new("applications"))
-            }
-            (Lifetime::Persistent(_), ResourceType::Icon) => {
-                self.persistent_dir.join(Path::new("icons"))
-            }
+        let root = match lifetime.scope() {
+            Scope::Process => &self.proc_dir,
+            Scope::Session => &self.session_dir,
+            Scope::Persistent => &self.persistent_dir,
+        };
+        match resource_type {
+            ResourceType::DesktopEntry => root.join(Path::new("applications")),
+            ResourceType::Icon => root.join(Path::new("icons")),
+            ResourceType::MimePackage => root.join(Path::new("mime/packages")),
+            ResourceType::DBusService => root.join(Path::new("dbus-1/services")),
+            // autostart entries are only looked up in config directories. ones that end with
+            // the session go under a runtime root added to `XDG_CONFIG_DIRS`, so they can't
+            // outlive it even if the daemon doesn't get to remove them
+            ResourceType::Autostart => match lifetime.scope() {
+                Scope::Persistent => self.autostart_dir.clone(),
+                _ => root.join(Path::new("config/autostart")),
+            },
         }
     }
     /// responsible for registering a desktop `entry` with a given `lifetime`. saves file as
-    /// `appid`.desktop, and can be referred to with the specified appid
+    /// `appid`.desktop, and can be referred to with the specified appid. if the appid is
+    /// already installed, the owner's namespace mode decides whether the entry is saved under
+    /// a namespaced file name instead. returns the problems found in the entry that weren't
+    /// bad enough to refuse it
     pub fn register_entry(
         &mut self,
         entry: &str,
         appid: &str,
         lifetime: Lifetime,
-    ) -> Result<(), EntryManagerError> {
+    ) -> Result<Vec<Diagnostic>, EntryManagerError> {
+        self.check_leases(&lifetime)?;
         // validate entry
-        let entry = validate_desktop_entry(entry, appid)?;
-        let desktop_file_path = self
+        let threshold = self.config.validation.threshold(&lifetime);
+        let (mut entry, diagnostics) = validate_desktop_entry(entry, appid, threshold)?;
+        let mut desktop_file_id = appid.to_string();
+        let mut duplicates = Vec::new();
+        for path in self.app_index.paths(appid) {
+            // links the symlink fallback made in `data_home` stand for the entries they point to
+            let path = self.link_target(&path).unwrap_or(path);
+            if !duplicates.contains(&path) {
+                duplicates.push(path);
+            }
+        }
+        let own_path = self
             .directory_for_lifetime(lifetime.clone(), ResourceType::DesktopEntry)
-            .as_path()
             .join(format!("{}.desktop", appid));
-        if desktop_file_path.exists() {
-            return Err(EntryManagerError::PathCollision(desktop_file_path));
+        if own_path.exists() && !duplicates.contains(&own_path) {
+            duplicates.push(own_path);
         }
-        // create applications directory just in case
-        let _ = create_dir_all(desktop_file_path.parent().unwrap());
-        std::fs::write(&desktop_file_path, entry.as_bytes())?;
-        // add entry to the list of entries recorded
-        match self.cache.entries.get_mut(&lifetime) {
-            None => {
-                self.cache
-                    .entries
-                    .insert(lifetime, vec![DesktopHandle::from(desktop_file_path)]);
+        // registered entries count even when their directories aren't in `XDG_DATA_DIRS`
+        for claimed in &self.cache.entries {
+            if claimed.handle.desktop_file_id() == format!("{}.desktop", appid)
+                && !duplicates.contains(&claimed.handle.path)
+            {
+                duplicates.push(claimed.handle.path.clone());
             }
-            Some(e) => {
-                e.push(DesktopHandle::from(desktop_file_path));
+        }
+        let mut namespace = false;
+        let mut replace = Vec::new();
+        let mut shared = None;
+        for path in duplicates {
+            let (source, tracked) = self.duplicate_source(&path, &lifetime);
+            let policy = self.config.duplicate_policy;
+            let action = match source {
+                DuplicateSource::SameOwner => policy.same_owner,
+                DuplicateSource::OtherOwner => policy.other_owner,
+                DuplicateSource::User => policy.user,
+                DuplicateSource::System => policy.system,
+            };
+            log::debug!(
+                "{} is installed at {:?} ({:?}): {:?}",
+                appid,
+                path,
+                source,
+                action
+            );
+            match action {
+                DuplicateAction::Reject => return Err(ValidationError::DuplicateAppID.into()),
+                DuplicateAction::Namespace => namespace = true,
+                DuplicateAction::Replace => replace.extend(tracked.map(|x| (x, path))),
+                DuplicateAction::Share if tracked.is_some() => shared = Some(path),
+                DuplicateAction::Share => namespace = true,
             }
         }
-        // resave cache
+        if namespace {
+            let owner = lifetime.namespace();
+            desktop_file_id = self
+                .namespace_mode(&owner)
+                .apply(appid, &owner)
+                .ok_or(ValidationError::DuplicateAppID)?;
+            if self.app_index.contains(&desktop_file_id) {
+                return Err(ValidationError::DuplicateAppID.into());
+            }
+            log::info!(
+                "{} is already installed, saving as {}",
+                appid,
+                desktop_file_id
+            );
+            // windows still report the original app id, keep matching them to this entry
+            entry = with_startup_wm_class(&entry, appid);
+        } else {
+            for (tracked_lifetime, path) in replace {
+                log::info!(
+                    "Replacing {:?} registered with {:?}",
+                    path,
+                    tracked_lifetime
+                );
+                // other owners may still claim it, so it's shared instead
+                if let Some(path) = self.remove_entry(&tracked_lifetime, &path)? {
+                    shared = Some(path);
+                }
+            }
+        }
+        if let (Some(path), false) = (shared, namespace) {
+            let index = self
+                .cache
+                .entries
+                .iter()
+                .position(|x| x.handle.path == path)
+                .unwrap();
+            let mut claims = self.cache.entries[index].claims.clone();
+            if !claims.contains(&lifetime) {
+                claims.push(lifetime);
+            }
+            log::info!(
+                "{} is already registered at {:?}, sharing it with {:?}",
+                appid,
+                path,
+                claims
+            );
+            self.set_entry_claims(index, claims.clone())?;
+            let handle = self.cache.entries[index].handle.clone();
+            self.set_dependent_claims(&handle, &claims)?;
+            self.save_cache()?;
+            return Ok(diagnostics);
+        }
+        let desktop_file_path = self
+            .directory_for_lifetime(lifetime.clone(), ResourceType::DesktopEntry)
+            .as_path()
+            .join(format!("{}.desktop", desktop_file_id));
+        if desktop_file_path.exists() {
+            return Err(EntryManagerError::PathCollision(desktop_file_path));
+        }
+        let entry = with_provenance(&entry, appid, &lifetime);
+        // create applications directory just in case
+        let _ = create_dir_all(desktop_file_path.parent().unwrap());
+        std::fs::write(&desktop_file_path, entry.as_bytes())?;
+        self.app_index.insert(&desktop_file_path);
+        let mut handle = DesktopHandle::new(appid, desktop_file_path);
+        handle.link =
+            self.export_symlink(&handle.path, lifetime.clone(), ResourceType::DesktopEntry);
+        // add entry to the list of entries recorded
+        self.pending.push(Change::Insert(
+            lifetime.clone(),
+            Record::Entry(handle.clone()),
+        ));
+        self.cache.entries.push(Claimed::new(handle, lifetime));
+        // resave cache
         self.save_cache()?;
         log::info!("Successfully entered: {} into the registry.", appid);
+        Ok(diagnostics)
+    }
+
+    /// the entry the symlink fallback linked to `path`, if `path` is one of its links. links
+    /// the cache doesn't track, i.e. after it was lost, are recognized by pointing into the
+    /// lifetime directories
+    fn link_target(&self, path: &Path) -> Option<PathBuf> {
+        self.cache
+            .entries
+            .iter()
+            .find(|x| x.handle.link.as_deref() == Some(path))
+            .map(|x| x.handle.path.clone())
+            .or_else(|| {
+                fs::read_link(path).ok().filter(|target| {
+                    [&self.proc_dir, &self.session_dir, &self.persistent_dir]
+                        .iter()
+                        .any(|x| target.starts_with(x))
+                })
+            })
+    }
+
+    /// where the installed entry at `path` came from, compared to an entry being registered
+    /// with `lifetime`, along with its lifetime if the daemon manages it
+    fn duplicate_source(
+        &self,
+        path: &Path,
+        lifetime: &Lifetime,
+    ) -> (DuplicateSource, Option<Lifetime>) {
+        // the claim of the same owner if it has one, as that's the one to replace
+        let tracked = self
+            .cache
+            .entries
+            .iter()
+            .find(|x| x.handle.path == path)
+            .map(|x| {
+                x.claims
+                    .iter()
+                    .find(|x| {
+                        *x == lifetime || (x.owner().is_some() && x.owner() == lifetime.owner())
+                    })
+                    .unwrap_or(&x.claims[0])
+                    .clone()
+            });
+        let own_tree = [&self.proc_dir, &self.session_dir, &self.persistent_dir]
+            .iter()
+            .any(|x| path.starts_with(x));
+        let source = match &tracked {
+            Some(tracked_lifetime)
+                if tracked_lifetime == lifetime
+                    || (tracked_lifetime.owner().is_some()
+                        && tracked_lifetime.owner() == lifetime.owner()) =>
+            {
+                DuplicateSource::SameOwner
+            }
+            Some(_) => DuplicateSource::OtherOwner,
+            None if own_tree => DuplicateSource::OtherOwner,
+            None if path.starts_with(&self.data_home) => DuplicateSource::User,
+            None => DuplicateSource::System,
+        };
+        (source, tracked)
+    }
+
+    /// drops the claim of `lifetime` on the registered entry at `path`, deleting it if that
+    /// was the last claim. returns where the entry is now if other lifetimes still claim it
+    fn remove_entry(
+        &mut self,
+        lifetime: &Lifetime,
+        path: &Path,
+    ) -> Result<Option<PathBuf>, EntryManagerError> {
+        let Some(index) = self
+            .cache
+            .entries
+            .iter()
+            .position(|x| x.handle.path == path)
+        else {
+            return Ok(None);
+        };
+        let mut claims = self.cache.entries[index].claims.clone();
+        claims.retain(|x| x != lifetime);
+        let path = self.set_entry_claims(index, claims.clone())?;
+        if path.is_some() {
+            let handle = self.cache.entries[index].handle.clone();
+            self.set_dependent_claims(&handle, &claims)?;
+        }
+        Ok(path)
+    }
+
+    /// sets the lifetimes claiming the registered entry at `index` to `claims`. the entry is
+    /// moved to the directories of its most durable claim and its provenance keys rewritten,
+    /// or it's deleted if there are no claims left. returns where the entry is now
+    fn set_entry_claims(
+        &mut self,
+        index: usize,
+        claims: Vec<Lifetime>,
+    ) -> Result<Option<PathBuf>, EntryManagerError> {
+        let old = self.cache.entries[index].clone();
+        let removed = old
+            .claims
+            .iter()
+            .map(|x| Change::Remove(x.clone(), Record::Entry(old.handle.clone())));
+        if claims.is_empty() {
+            self.pending.extend(removed);
+            self.cache.entries.remove(index);
+            self.app_index.remove(&old.handle.path);
+            old.handle.delete_self()?;
+            return Ok(None);
+        }
+        let lifetime = primary_claim(&claims).clone();
+        let mut handle = old.handle.clone();
+        let target = self.relocate(
+            &handle.path,
+            old.lifetime(),
+            &lifetime,
+            ResourceType::DesktopEntry,
+        );
+        let entry = fs::read_to_string(&handle.path)?;
+        write_atomic(&target, with_claims(&entry, &lifetime, &claims).as_bytes())?;
+        if target != handle.path {
+            fs::remove_file(&handle.path)?;
+            self.app_index.remove(&handle.path);
+            self.app_index.insert(&target);
+            if let Some(link) = handle.link.take() {
+                remove_link(&link, &handle.path)?;
+                handle.link =
+                    self.export_symlink(&target, lifetime.clone(), ResourceType::DesktopEntry);
+            }
+            handle.path = target;
+        }
+        self.pending.extend(removed);
+        self.pending.extend(
+            claims
+                .iter()
+                .map(|x| Change::Insert(x.clone(), Record::Entry(handle.clone()))),
+        );
+        let path = handle.path.clone();
+        self.cache.entries[index] = Claimed { handle, claims };
+        Ok(Some(path))
+    }
+
+    /// sets the lifetimes claiming the registered icon at `index` to `claims`. the icon is
+    /// moved to the directories of its most durable claim and its sidecar rewritten, or it's
+    /// deleted if there are no claims left. returns where the icon is now
+    fn set_icon_claims(
+        &mut self,
+        index: usize,
+        claims: Vec<Lifetime>,
+    ) -> Result<Option<PathBuf>, EntryManagerError> {
+        let old = self.cache.icons[index].clone();
+        let removed = old
+            .claims
+            .iter()
+            .map(|x| Change::Remove(x.clone(), Record::Icon(old.handle.clone())));
+        if claims.is_empty() {
+            self.pending.extend(removed);
+            self.cache.icons.remove(index);
+            old.handle.delete_self()?;
+            return Ok(None);
+        }
+        let lifetime = primary_claim(&claims).clone();
+        let mut handle = old.handle.clone();
+        let target = self.relocate(
+            &handle.icon_path,
+            old.lifetime(),
+            &lifetime,
+            ResourceType::Icon,
+        );
+        if target != handle.icon_path {
+            move_file(&handle.icon_path, &target)?;
+            remove_sidecar(&handle.icon_path)?;
+            if let Some(link) = handle.link.take() {
+                remove_link(&link, &handle.icon_path)?;
+                handle.link = self.export_symlink(&target, lifetime.clone(), ResourceType::Icon);
+            }
+            handle.icon_path = target;
+        }
+        write_claims_sidecar(&handle.icon_path, &claims, &handle.icon_name)?;
+        self.pending.extend(removed);
+        self.pending.extend(
+            claims
+                .iter()
+                .map(|x| Change::Insert(x.clone(), Record::Icon(handle.clone()))),
+        );
+        if handle.icon_path != old.handle.icon_path {
+            self.write_icon_theme_index(old.lifetime().clone())?;
+            self.write_icon_theme_index(lifetime)?;
+        }
+        let path = handle.icon_path.clone();
+        self.cache.icons[index] = Claimed { handle, claims };
+        Ok(Some(path))
+    }
+
+    /// sets the lifetimes claiming the registered resource at `index` to `claims`. the file is
+    /// moved to the directories of its most durable claim and its provenance rewritten, or
+    /// it's deleted if there are no claims left. returns where the file is now
+    fn set_resource_claims(
+        &mut self,
+        index: usize,
+        claims: Vec<Lifetime>,
+    ) -> Result<Option<PathBuf>, EntryManagerError> {
+        let old = self.cache.resources[index].clone();
+        let resource_type = old.handle.resource_type;
+        let removed = old
+            .claims
+            .iter()
+            .map(|x| Change::Remove(x.clone(), Record::Resource(old.handle.clone())));
+        if claims.is_empty() {
+            self.pending.extend(removed);
+            self.cache.resources.remove(index);
+            old.handle.clone().delete_self()?;
+            match resource_type {
+                ResourceType::MimePackage => self.refresh_mime_database(old.lifetime().clone()),
+                ResourceType::DBusService => self.bus_reload_pending = true,
+                _ => {}
+            }
+            return Ok(None);
+        }
+        let lifetime = primary_claim(&claims).clone();
+        let mut handle = old.handle.clone();
+        let target = self.relocate(&handle.path, old.lifetime(), &lifetime, resource_type);
+        if target != handle.path {
+            move_file(&handle.path, &target)?;
+            remove_sidecar(&handle.path)?;
+            handle.path = target;
+            match resource_type {
+                ResourceType::MimePackage => {
+                    self.refresh_mime_database(old.lifetime().clone());
+                    self.refresh_mime_database(lifetime.clone());
+                }
+                ResourceType::DBusService => self.bus_reload_pending = true,
+                _ => {}
+            }
+        }
+        // autostart entries record their lifetime in their own keys, as entries do
+        if resource_type == ResourceType::Autostart {
+            let entry = fs::read_to_string(&handle.path)?;
+            write_atomic(&handle.path, with_lifetime(&entry, &lifetime).as_bytes())?;
+        } else {
+            write_claims_sidecar(&handle.path, &claims, &handle.name)?;
+        }
+        self.pending.extend(removed);
+        self.pending.extend(
+            claims
+                .iter()
+                .map(|x| Change::Insert(x.clone(), Record::Resource(handle.clone()))),
+        );
+        let path = handle.path.clone();
+        self.cache.resources[index] = Claimed { handle, claims };
+        Ok(Some(path))
+    }
+
+    /// sets the lifetimes claiming the default application association at `index` to
+    /// `claims`, or removes it if there are no claims left, and rewrites the `mimeapps.list`
+    /// files it's listed in
+    fn set_association_claims(
+        &mut self,
+        index: usize,
+        claims: Vec<Lifetime>,
+    ) -> Result<(), EntryManagerError> {
+        let old = self.cache.associations[index].clone();
+        self.pending.extend(
+            old.claims
+                .iter()
+                .map(|x| Change::Remove(x.clone(), Record::Association(old.handle.clone()))),
+        );
+        if claims.is_empty() {
+            self.cache.associations.remove(index);
+        } else {
+            let lifetime = primary_claim(&claims).clone();
+            self.pending.extend(
+                claims
+                    .iter()
+                    .map(|x| Change::Insert(x.clone(), Record::Association(old.handle.clone()))),
+            );
+            self.cache.associations[index] = Claimed {
+                handle: old.handle.clone(),
+                claims,
+            };
+            self.write_mimeapps_list(lifetime)?;
+        }
+        self.write_mimeapps_list(old.lifetime().clone())
+    }
+
+    /// sets the lifetimes claiming the d-bus service and the default application associations
+    /// of the registered entry `handle` to `claims`, which can't be empty, so they're kept for
+    /// as long as the entry is
+    fn set_dependent_claims(
+        &mut self,
+        handle: &DesktopHandle,
+        claims: &[Lifetime],
+    ) -> Result<(), EntryManagerError> {
+        for index in 0..self.cache.resources.len() {
+            let resource = &self.cache.resources[index].handle;
+            if resource.resource_type == ResourceType::DBusService && resource.name == handle.appid
+            {
+                self.set_resource_claims(index, claims.to_vec())?;
+            }
+        }
+        let desktop_file = handle.desktop_file_id();
+        for index in 0..self.cache.associations.len() {
+            if self.cache.associations[index].handle.desktop_file == desktop_file {
+                self.set_association_claims(index, claims.to_vec())?;
+            }
+        }
         Ok(())
     }
 
-    /// responsible for registering an application icon with the given `icon_name`
+    /// links `path` into the matching directory of `data_home` when symlink exports are on.
+    /// returns the link, or `None` if no link was made
+    fn export_symlink(
+        &self,
+        path: &Path,
+        lifetime: Lifetime,
+        resource_type: ResourceType,
+    ) -> Option<PathBuf> {
+        if !self.symlink_exports {
+            return None;
+        }
+        let relative = path
+            .strip_prefix(self.directory_for_lifetime(lifetime, resource_type))
+            .ok()?;
+        let link = match resource_type {
+            ResourceType::DesktopEntry => self.data_home.join("applications").join(relative),
+            ResourceType::Icon => self.data_home.join("icons").join(relative),
+            _ => return None,
+        };
+        if link.exists() || link.is_symlink() {
+            log::warn!("{:?} already exists, not exporting {:?}", link, path);
+            return None;
+        }
+        let _ = create_dir_all(link.parent().unwrap());
+        match symlink(path, &link) {
+            Ok(_) => Some(link),
+            Err(e) => {
+                log::warn!("could not link {:?} to {:?}: {:?}", path, link, e);
+                None
+            }
+        }
+    }
+
+    /// turns on symlink exports and links every registered entry and icon that isn't linked
+    /// into `data_home` yet
+    pub fn export_symlinks(&mut self) -> Result<(), EntryManagerError> {
+        self.symlink_exports = true;
+        let mut entries = self.cache.entries.clone();
+        for claimed in entries.iter_mut().filter(|x| x.handle.link.is_none()) {
+            claimed.handle.link = self.export_symlink(
+                &claimed.handle.path,
+                claimed.lifetime().clone(),
+                ResourceType::DesktopEntry,
+            );
+        }
+        let mut icons = self.cache.icons.clone();
+        for claimed in icons.iter_mut().filter(|x| x.handle.link.is_none()) {
+            claimed.handle.link = self.export_symlink(
+                &claimed.handle.icon_path,
+                claimed.lifetime().clone(),
+                ResourceType::Icon,
+            );
+        }
+        self.cache.entries = entries;
+        self.cache.icons = icons;
+        self.pending.push(Change::All);
+        self.save_cache()
+    }
+
+    /// responsible for registering an icon with the given `icon_name` in the theme `context`
     /// icon will have the specified `lifetime`
     pub fn register_icon(
         &mut self,
         icon_name: &str,
         icon_data: &[u8],
+        context: IconContext,
         lifetime: Lifetime,
     ) -> Result<(), EntryManagerError> {
+        self.check_leases(&lifetime)?;
         let icon_path = if let Ok(img) = image::io::Reader::new(std::io::Cursor::new(icon_data))
             .with_guessed_format()
             .unwrap()
             .decode()
         {
             // image sent as bytes
-            self.icon_as_bytes(&img, icon_name, lifetime.clone())?
+            self.icon_as_bytes(&img, icon_name, context, lifetime.clone())?
         } else if let Ok(text_data) = String::from_utf8(icon_data.into()) {
             // image sent as svg
-            self.icon_as_svg(text_data, icon_name, lifetime.clone())?
+            self.icon_as_svg(text_data, icon_name, context, lifetime.clone())?
         } else {
             return Err(EntryManagerError::IconValidation(
                 IconValidationError::NoTypeFound,
             ));
         };
-        // add entry to the list of entries recorded
-        match self.cache.icons.get_mut(&lifetime) {
-            None => {
-                self.cache
-                    .icons
-                    .insert(lifetime, vec![IconHandle::from(icon_path)]);
-            }
-            Some(e) => {
-                e.push(IconHandle::from(icon_path));
+        if let Some(index) = self
+            .cache
+            .icons
+            .iter()
+            .position(|x| x.handle.icon_path == icon_path)
+        {
+            // another lifetime registered the same icon, which is kept until both are done
+            let mut claims = self.cache.icons[index].claims.clone();
+            if claims.contains(&lifetime) {
+                return Err(EntryManagerError::PathCollision(icon_path));
             }
+            claims.push(lifetime);
+            log::info!(
+                "{:?} is already registered, sharing it with {:?}",
+                icon_path,
+                claims
+            );
+            self.set_icon_claims(index, claims)?;
+            self.save_cache()?;
+            return Ok(());
+        }
+        let mut handle = IconHandle::from(icon_path);
+        write_sidecar(&handle.icon_path, &lifetime, &handle.icon_name)?;
+        handle.link = self.export_symlink(&handle.icon_path, lifetime.clone(), ResourceType::Icon);
+        // add entry to the list of entries recorded
+        self.pending.push(Change::Insert(
+            lifetime.clone(),
+            Record::Icon(handle.clone()),
+        ));
+        self.cache
+            .icons
+            .push(Claimed::new(handle, lifetime.clone()));
+        if let Err(e) = self.write_icon_theme_index(lifetime) {
+            log::warn!("could not update index.theme: {:?}", e);
         }
         // resave cache
         self.save_cache()?;
         Ok(())
     }
 
+    /// the path of the registered icon at `relative` in the icon directory of the lifetime it
+    /// is kept for, if there is one
+    fn registered_icon(&self, relative: &Path) -> Option<PathBuf> {
+        self.cache
+            .icons
+            .iter()
+            .map(|x| {
+                (
+                    self.directory_for_lifetime(x.lifetime().clone(), ResourceType::Icon),
+                    &x.handle.icon_path,
+                )
+            })
+            .find(|(dir, path)| path.strip_prefix(dir).is_ok_and(|x| x == relative))
+            .map(|(_, path)| path.clone())
+    }
+
     fn icon_as_bytes(
         &self,
         img: &DynamicImage,
         icon_name: &str,
+        context: IconContext,
         lifetime: Lifetime,
     ) -> Result<PathBuf, EntryManagerError> {
         log::info!("{} is a valid image as bytes", icon_name);
@@ -271,14 +1841,19 @@ impl EntryManager {
         } else {
             img.to_owned()
         };
+        let relative = PathBuf::from(format!(
+            "hicolor/{}x{}/{}/{}.png",
+            img.width(),
+            img.height(),
+            context.directory(),
+            icon_name
+        ));
+        if let Some(shared) = self.registered_icon(&relative) {
+            return Ok(shared);
+        }
         let icon_path = self
             .directory_for_lifetime(lifetime, ResourceType::Icon)
-            .join(Path::new(&format!(
-                "hicolor/{}x{}/apps/{}.png",
-                img.width(),
-                img.height(),
-                icon_name
-            )));
+            .join(relative);
         let _ = create_dir_all(icon_path.parent().unwrap());
         if icon_path.exists() {
             return Err(EntryManagerError::PathCollision(icon_path));
@@ -291,16 +1866,22 @@ impl EntryManager {
         &self,
         svg_text: String,
         icon_name: &str,
+        context: IconContext,
         lifetime: Lifetime,
     ) -> Result<PathBuf, EntryManagerError> {
         // check for valid svg
         svg::read(&svg_text)?;
+        let relative = PathBuf::from(format!(
+            "hicolor/scalable/{}/{}.svg",
+            context.directory(),
+            icon_name
+        ));
+        if let Some(shared) = self.registered_icon(&relative) {
+            return Ok(shared);
+        }
         let icon_path = self
             .directory_for_lifetime(lifetime, ResourceType::Icon)
-            .join(Path::new(&format!(
-                "hicolor/scalable/apps/{}.svg",
-                icon_name
-            )));
+            .join(relative);
         let _ = create_dir_all(icon_path.parent().unwrap());
         if icon_path.exists() {
             return Err(EntryManagerError::PathCollision(icon_path));
@@ -309,114 +1890,1894 @@ impl EntryManager {
         Ok(icon_path)
     }
 
-    pub fn remove_lifetime(&mut self, lifetime: Lifetime) -> Result<(), EntryManagerError> {
-        log::info!("Deleting lifetime {:?}", lifetime);
-        if let Some(entries) = self.cache.entries.get(&lifetime) {
-            for entry in entries {
-                match entry.clone().delete_self() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("problem deleting entry {:?} : {:?}", entry.appid, e);
-                    }
-                }
-            }
-            self.cache.entries.remove(&lifetime);
+    /// responsible for registering a shared-mime-info package `package` with a given
+    /// `lifetime`. saves file as `name`.xml in the lifetime's `mime/packages` directory
+    pub fn register_mime_package(
+        &mut self,
+        package: &str,
+        name: &str,
+        lifetime: Lifetime,
+    ) -> Result<(), EntryManagerError> {
+        self.check_leases(&lifetime)?;
+        let package =
+            validate_mime_package(package, name).map_err(EntryManagerError::MimeValidation)?;
+        let package_path = self
+            .directory_for_lifetime(lifetime.clone(), ResourceType::MimePackage)
+            .join(format!("{}.xml", name));
+        if package_path.exists() {
+            return Err(EntryManagerError::PathCollision(package_path));
         }
-        if let Some(icons) = self.cache.icons.get(&lifetime) {
-            for icon in icons {
-                match icon.clone().delete_self() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("problem deleting icon {:?} : {:?}", icon.icon_name, e);
-                    }
-                }
-            }
-            self.cache.icons.remove(&lifetime);
+        let _ = create_dir_all(package_path.parent().unwrap());
+        fs::write(&package_path, package.as_bytes())?;
+        write_sidecar(&package_path, &lifetime, name)?;
+        let handle = ResourceHandle {
+            resource_type: ResourceType::MimePackage,
+            name: name.to_string(),
+            path: package_path,
+        };
+        self.pending.push(Change::Insert(
+            lifetime.clone(),
+            Record::Resource(handle.clone()),
+        ));
+        self.cache
+            .resources
+            .push(Claimed::new(handle, lifetime.clone()));
+        self.refresh_mime_database(lifetime);
+        // resave cache
+        self.save_cache()?;
+        log::info!(
+            "Successfully entered mime package: {} into the registry.",
+            name
+        );
+        Ok(())
+    }
+
+    /// responsible for registering an autostart `entry` with a given `lifetime`. saves file
+    /// as `appid`.desktop in the user's autostart directory. persistent autostart entries are
+    /// only accepted with a permissive exec policy
+    pub fn register_autostart(
+        &mut self,
+        entry: &str,
+        appid: &str,
+        lifetime: Lifetime,
+    ) -> Result<(), EntryManagerError> {
+        self.check_leases(&lifetime)?;
+        if lifetime.outlives_session() && self.config.exec_policy != ExecPolicy::Permissive {
+            return Err(EntryManagerError::PolicyDenied(
+                "autostart entries outliving the session are not allowed by the exec policy"
+                    .to_string(),
+            ));
+        }
+        let entry = validate_autostart_entry(entry, appid)?;
+        let autostart_path = self
+            .directory_for_lifetime(lifetime.clone(), ResourceType::Autostart)
+            .join(format!("{}.desktop", appid));
+        if autostart_path.exists() {
+            return Err(EntryManagerError::PathCollision(autostart_path));
         }
+        let entry = with_provenance(&entry, appid, &lifetime);
+        let _ = create_dir_all(autostart_path.parent().unwrap());
+        fs::write(&autostart_path, entry.as_bytes())?;
+        let handle = ResourceHandle {
+            resource_type: ResourceType::Autostart,
+            name: appid.to_string(),
+            path: autostart_path,
+        };
+        self.pending.push(Change::Insert(
+            lifetime.clone(),
+            Record::Resource(handle.clone()),
+        ));
+        self.cache.resources.push(Claimed::new(handle, lifetime));
+        // resave cache
+        self.save_cache()?;
+        log::info!(
+            "Successfully entered autostart: {} into the registry.",
+            appid
+        );
         Ok(())
     }
 
-    pub fn reset_session(&mut self) -> Result<(), EntryManagerError> {
-        for lifetime in self
-            .cache
+    /// how `owner` wants entries with an already installed appid to be saved
+    pub fn namespace_mode(&self, owner: &str) -> NamespaceMode {
+        self.cache
+            .namespace_modes
+            .get(owner)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// sets how `owner` wants entries with an already installed appid to be saved
+    pub fn set_namespace_mode(
+        &mut self,
+        owner: &str,
+        mode: NamespaceMode,
+    ) -> Result<(), EntryManagerError> {
+        if mode == NamespaceMode::default() {
+            self.cache.namespace_modes.remove(owner);
+            self.pending
+                .push(Change::NamespaceMode(owner.to_string(), None));
+        } else {
+            self.cache.namespace_modes.insert(owner.to_string(), mode);
+            self.pending
+                .push(Change::NamespaceMode(owner.to_string(), Some(mode)));
+        }
+        self.save_cache()
+    }
+
+    /// the handle of the registered entry `appid`, and the lifetime it's kept for
+    pub fn entry_for_appid(&self, appid: &str) -> Option<(Lifetime, DesktopHandle)> {
+        self.cache
             .entries
-            .clone()
             .iter()
-            .map(|x| x.0)
-            .chain(self.cache.icons.clone().iter().map(|x| x.0))
-            .filter_map(|x| {
-                if matches!(x, Lifetime::Session(_)) {
-                    Some(x.clone())
-                } else {
-                    None
-                }
-            })
-        {
-            self.remove_lifetime(lifetime.clone())?;
+            .find(|x| x.handle.appid == appid)
+            .map(|x| (x.lifetime().clone(), x.handle.clone()))
+    }
+
+    /// every lifetime claiming the registered entry `appid`
+    fn entry_claims(&self, appid: &str) -> Option<Vec<Lifetime>> {
+        self.cache
+            .entries
+            .iter()
+            .find(|x| x.handle.appid == appid)
+            .map(|x| x.claims.clone())
+    }
+
+    /// responsible for registering the d-bus activation file `service` for the registered
+    /// entry `appid`. saves file as `appid`.service in the `dbus-1/services` directory of the
+    /// entry's lifetime. the service is claimed by every lifetime claiming the entry, so it
+    /// goes away with the entry
+    pub fn register_dbus_service(
+        &mut self,
+        service: &str,
+        appid: &str,
+    ) -> Result<(), EntryManagerError> {
+        let claims = self
+            .entry_claims(appid)
+            .ok_or(EntryManagerError::UnknownAppID(appid.to_string()))?;
+        let lifetime = primary_claim(&claims).clone();
+        let service =
+            validate_dbus_service(service, appid).map_err(EntryManagerError::ServiceValidation)?;
+        let service_path = self
+            .directory_for_lifetime(lifetime.clone(), ResourceType::DBusService)
+            .join(format!("{}.service", appid));
+        if service_path.exists() {
+            return Err(EntryManagerError::PathCollision(service_path));
         }
+        let _ = create_dir_all(service_path.parent().unwrap());
+        fs::write(&service_path, service.as_bytes())?;
+        write_claims_sidecar(&service_path, &claims, appid)?;
+        let handle = ResourceHandle {
+            resource_type: ResourceType::DBusService,
+            name: appid.to_string(),
+            path: service_path,
+        };
+        self.pending.extend(
+            claims
+                .iter()
+                .map(|x| Change::Insert(x.clone(), Record::Resource(handle.clone()))),
+        );
+        self.cache.resources.push(Claimed { handle, claims });
+        self.bus_reload_pending = true;
+        // resave cache
         self.save_cache()?;
+        log::info!(
+            "Successfully entered d-bus service: {} into the registry.",
+            appid
+        );
         Ok(())
     }
 
-    pub fn save_cache(&self) -> Result<(), EntryManagerError> {
-        let conf_str = ron::ser::to_string_pretty(&self.cache, ron::ser::PrettyConfig::default())?;
-        fs::write(&self.config_file, conf_str)?;
+    /// makes the registered entry `appid` the default application for `mime_type`. the
+    /// association is claimed by every lifetime claiming the entry, and is written to the
+    /// `mimeapps.list` next to it
+    pub fn set_default_application(
+        &mut self,
+        mime_type: &str,
+        appid: &str,
+    ) -> Result<(), EntryManagerError> {
+        if mime_type.split('/').count() != 2 || mime_type.contains(['=', ';']) {
+            return Err(EntryManagerError::MimeValidation(format!(
+                "'{}' is not a valid mime type",
+                mime_type
+            )));
+        }
+        let (lifetime, handle) = self
+            .entry_for_appid(appid)
+            .ok_or(EntryManagerError::UnknownAppID(appid.to_string()))?;
+        let claims = self.entry_claims(appid).unwrap_or_default();
+        self.remove_default_application(mime_type)?;
+        let association = MimeAssociation {
+            mime_type: mime_type.to_string(),
+            desktop_file: handle
+                .path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+        };
+        self.pending.extend(
+            claims
+                .iter()
+                .map(|x| Change::Insert(x.clone(), Record::Association(association.clone()))),
+        );
+        self.cache.associations.push(Claimed {
+            handle: association,
+            claims,
+        });
+        self.write_mimeapps_list(lifetime)?;
+        self.save_cache()?;
+        log::info!("{} is now the default application for {}", appid, mime_type);
         Ok(())
     }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct DesktopHandle {
-    pub appid: String,
-    pub path: PathBuf,
-}
 
-impl Hash for DesktopHandle {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.appid.hash(state);
+    /// the desktop file the daemon has made the default application for `mime_type`, if any
+    pub fn default_application(&self, mime_type: &str) -> Option<String> {
+        self.cache
+            .associations
+            .iter()
+            .find(|x| x.handle.mime_type == mime_type)
+            .map(|x| x.handle.desktop_file.clone())
     }
-}
 
-impl From<PathBuf> for DesktopHandle {
-    fn from(value: PathBuf) -> Self {
-        Self {
-            appid: value.file_stem().unwrap().to_str().unwrap().to_string(),
-            path: value,
+    /// removes the default application the daemon set for `mime_type`, if any
+    pub fn remove_default_application(&mut self, mime_type: &str) -> Result<(), EntryManagerError> {
+        while let Some(index) = self
+            .cache
+            .associations
+            .iter()
+            .position(|x| x.handle.mime_type == mime_type)
+        {
+            self.set_association_claims(index, Vec::new())?;
         }
+        self.save_cache()?;
+        Ok(())
     }
-}
 
-impl DesktopHandle {
-    fn delete_self(self) -> Result<(), std::io::Error> {
-        fs::remove_file(&self.path)?;
+    /// regenerates the `mimeapps.list` in the applications directory for `lifetime` from
+    /// every association stored in that directory. the associations and the lifetimes
+    /// claiming them are kept in a sidecar file next to it
+    fn write_mimeapps_list(&self, lifetime: Lifetime) -> Result<(), EntryManagerError> {
+        let applications_dir = self.directory_for_lifetime(lifetime, ResourceType::DesktopEntry);
+        let mut associations = HashMap::<&Lifetime, Vec<&MimeAssociation>>::new();
+        let mut defaults = Vec::new();
+        for claimed in self.cache.associations.iter().filter(|x| {
+            self.directory_for_lifetime(x.lifetime().clone(), ResourceType::DesktopEntry)
+                == applications_dir
+        }) {
+            for claim in &claimed.claims {
+                associations.entry(claim).or_default().push(&claimed.handle);
+            }
+            defaults.push(&claimed.handle);
+        }
+        let mimeapps_list = applications_dir.join("mimeapps.list");
+        if associations.is_empty() {
+            if mimeapps_list.exists() {
+                fs::remove_file(&mimeapps_list)?;
+            }
+            remove_sidecar(&mimeapps_list)?;
+            return Ok(());
+        }
+        defaults.sort_by(|a, b| a.mime_type.cmp(&b.mime_type));
+        let mut list = String::from("[Default Applications]\n");
+        for association in defaults {
+            list.push_str(&format!(
+                "{}={};\n",
+                association.mime_type, association.desktop_file
+            ));
+        }
+        let _ = create_dir_all(&applications_dir);
+        fs::write(&mimeapps_list, list)?;
+        fs::write(
+            sidecar_path(&mimeapps_list),
+            ron::ser::to_string_pretty(&associations, ron::ser::PrettyConfig::default())?,
+        )?;
         Ok(())
     }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct IconHandle {
-    pub icon_name: String,
-    pub icon_path: PathBuf,
-}
 
-impl Hash for IconHandle {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.icon_name.hash(state);
+    /// queues `update-mime-database` to be run on the `mime` directory for `lifetime`, once
+    /// the manager is unlocked
+    fn refresh_mime_database(&mut self, lifetime: Lifetime) {
+        let packages_dir = self.directory_for_lifetime(lifetime, ResourceType::MimePackage);
+        self.mime_refresh_pending
+            .insert(packages_dir.parent().unwrap().to_owned());
     }
-}
 
-impl From<PathBuf> for IconHandle {
-    fn from(value: PathBuf) -> Self {
-        Self {
-            icon_name: value.file_stem().unwrap().to_str().unwrap().to_string(),
-            icon_path: value,
+    /// regenerates `hicolor/index.theme` in the icon directory for `lifetime`, listing every
+    /// `<size>/<context>` directory that currently exists there
+    fn write_icon_theme_index(&self, lifetime: Lifetime) -> Result<(), EntryManagerError> {
+        let theme_dir = self
+            .directory_for_lifetime(lifetime, ResourceType::Icon)
+            .join("hicolor");
+        if !theme_dir.exists() {
+            return Ok(());
+        }
+        let mut directories = Vec::new();
+        for size_dir in fs::read_dir(&theme_dir)?.flatten() {
+            if !size_dir.path().is_dir() {
+                continue;
+            }
+            let size = size_dir.file_name().to_string_lossy().to_string();
+            for context_dir in fs::read_dir(size_dir.path())?.flatten() {
+                let context_name = context_dir.file_name().to_string_lossy().to_string();
+                if let Ok(context) = IconContext::from_str(&context_name) {
+                    if context.directory() == context_name && context_dir.path().is_dir() {
+                        directories.push((size.clone(), context));
+                    }
+                }
+            }
+        }
+        directories.sort_by(|a, b| (&a.0, a.1.directory()).cmp(&(&b.0, b.1.directory())));
+        let mut index = String::from("[Icon Theme]\nName=Hicolor\nComment=Fallback icon theme\n");
+        index.push_str(&format!(
+            "Directories={}\n",
+            directories
+                .iter()
+                .map(|(size, context)| format!("{}/{}", size, context.directory()))
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+        for (size, context) in directories {
+            index.push_str(&format!("\n[{}/{}]\n", size, context.directory()));
+            if size == "scalable" {
+                index.push_str("Size=128\nMinSize=8\nMaxSize=512\nType=Scalable\n");
+            } else {
+                let pixels = size.split('x').next().unwrap_or_default();
+                index.push_str(&format!("Size={}\nType=Threshold\n", pixels));
+            }
+            index.push_str(&format!("Context={}\n", context.theme_context()));
         }
+        fs::write(theme_dir.join("index.theme"), index)?;
+        Ok(())
     }
-}
 
-impl IconHandle {
-    fn delete_self(self) -> Result<(), std::io::Error> {
-        fs::remove_file(&self.icon_path)?;
+    pub fn remove_lifetime(&mut self, lifetime: Lifetime) -> Result<(), EntryManagerError> {
+        log::info!("Deleting lifetime {:?}", lifetime);
+        self.pending.push(Change::RemoveLifetime(lifetime.clone()));
+        for index in (0..self.cache.entries.len()).rev() {
+            let claimed = &self.cache.entries[index];
+            if !claimed.claims.contains(&lifetime) {
+                continue;
+            }
+            let appid = claimed.handle.appid.clone();
+            let claims = claimed
+                .claims
+                .iter()
+                .filter(|x| **x != lifetime)
+                .cloned()
+                .collect();
+            if let Err(e) = self.set_entry_claims(index, claims) {
+                log::error!("problem releasing entry {:?} : {:?}", appid, e);
+            }
+        }
+        let mut icons_released = false;
+        for index in (0..self.cache.icons.len()).rev() {
+            let claimed = &self.cache.icons[index];
+            if !claimed.claims.contains(&lifetime) {
+                continue;
+            }
+            let icon_name = claimed.handle.icon_name.clone();
+            let claims = claimed
+                .claims
+                .iter()
+                .filter(|x| **x != lifetime)
+                .cloned()
+                .collect();
+            if let Err(e) = self.set_icon_claims(index, claims) {
+                log::error!("problem releasing icon {:?} : {:?}", icon_name, e);
+            }
+            icons_released = true;
+        }
+        if icons_released {
+            if let Err(e) = self.write_icon_theme_index(lifetime.clone()) {
+                log::warn!("could not update index.theme: {:?}", e);
+            }
+        }
+        for index in (0..self.cache.resources.len()).rev() {
+            let claimed = &self.cache.resources[index];
+            if !claimed.claims.contains(&lifetime) {
+                continue;
+            }
+            let (resource_type, name) = (claimed.handle.resource_type, claimed.handle.name.clone());
+            let claims = claimed
+                .claims
+                .iter()
+                .filter(|x| **x != lifetime)
+                .cloned()
+                .collect();
+            if let Err(e) = self.set_resource_claims(index, claims) {
+                log::error!("problem releasing {:?} {:?} : {:?}", resource_type, name, e);
+            }
+        }
+        for index in (0..self.cache.associations.len()).rev() {
+            let claimed = &self.cache.associations[index];
+            if !claimed.claims.contains(&lifetime) {
+                continue;
+            }
+            let claims = claimed
+                .claims
+                .iter()
+                .filter(|x| **x != lifetime)
+                .cloned()
+                .collect();
+            self.set_association_claims(index, claims)?;
+        }
         Ok(())
     }
+
+    /// moves the expiring resources of `owner` to `deadline`, in seconds since the unix epoch.
+    /// the deadline has to be in the future, and can't be earlier than any of the current ones
+    pub fn extend_deadline(&mut self, owner: &str, deadline: u64) -> Result<(), EntryManagerError> {
+        if deadline <= unix_now() {
+            return Err(EntryManagerError::InvalidLifetime(format!(
+                "deadline {} has already passed",
+                deadline
+            )));
+        }
+        let new = Lifetime::Expiring {
+            owner: owner.to_string(),
+            deadline,
+        };
+        let leaves = self
+            .cache
+            .leaves()
+            .into_iter()
+            .filter(|x| matches!(x, Lifetime::Expiring { owner: o, .. } if o == owner))
+            .collect::<Vec<_>>();
+        if leaves.is_empty() {
+            return Err(EntryManagerError::UnknownOwner(owner.to_string()));
+        }
+        if let Some(latest) = leaves
+            .iter()
+            .filter_map(|x| match x {
+                Lifetime::Expiring { deadline, .. } => Some(*deadline),
+                _ => None,
+            })
+            .max()
+            .filter(|x| *x > deadline)
+        {
+            return Err(EntryManagerError::InvalidLifetime(format!(
+                "deadline {} is earlier than the current deadline {}",
+                deadline, latest
+            )));
+        }
+        for leaf in leaves.into_iter().filter(|x| *x != new) {
+            log::info!("Moving {:?} to {:?}", leaf, new);
+            self.relabel_leaf(&leaf, &new)?;
+        }
+        self.save_cache()
+    }
+
+    /// starts a lease that lasts `interval` seconds from each renewal, and may be renewed up to
+    /// `grace` seconds late. returns its id and the token to renew it with
+    pub fn new_lease(
+        &mut self,
+        interval: u64,
+        grace: u64,
+    ) -> Result<(String, String), EntryManagerError> {
+        if interval == 0 {
+            return Err(EntryManagerError::InvalidLifetime(
+                "leases need an interval of at least a second".to_string(),
+            ));
+        }
+        // the token is all a client needs to keep the lease alive, so it shouldn't be guessable
+        let token = fs::read_to_string("/proc/sys/kernel/random/uuid")?
+            .trim()
+            .to_string();
+        let id = Lease::id(&token);
+        let now = unix_now();
+        let lease = Lease {
+            interval,
+            grace,
+            expires: now.saturating_add(interval),
+        };
+        log::info!("Starting lease {} with {:?}", id, lease);
+        self.cache.leases.insert(id.clone(), lease.clone());
+        self.pending.push(Change::Lease(id.clone(), Some(lease)));
+        // the last checkpoint may be from before the daemon had any leases to keep track of
+        self.checkpoint_leases(now)?;
+        Ok((id, token))
+    }
+
+    /// renews the lease with `token` for another interval. returns when it expires now, in
+    /// seconds since the unix epoch
+    pub fn renew_lease(&mut self, token: &str) -> Result<u64, EntryManagerError> {
+        let now = unix_now();
+        let id = Lease::id(token);
+        let Some(lease) = self.cache.leases.get_mut(&id).filter(|x| !x.lapsed(now)) else {
+            return Err(EntryManagerError::UnknownLease(id));
+        };
+        lease.expires = now.saturating_add(lease.interval);
+        let lease = lease.clone();
+        log::debug!("Renewed lease {} until {}", id, lease.expires);
+        self.pending.push(Change::Lease(id, Some(lease.clone())));
+        self.save_cache()?;
+        Ok(lease.expires)
+    }
+
+    /// ends the lease with `token` right away, removing everything that ends with it
+    pub fn release_lease(&mut self, token: &str) -> Result<(), EntryManagerError> {
+        let id = Lease::id(token);
+        if !self.cache.leases.contains_key(&id) {
+            return Err(EntryManagerError::UnknownLease(id));
+        }
+        log::info!("Releasing lease {}", id);
+        self.end_lease(&id)?;
+        self.save_cache()
+    }
+
+    /// ends every lease that lapsed at `now`, and every lease lifetime whose lease is gone,
+    /// i.e. because it was lost with the cache. returns whether any lease ended
+    pub fn lapse_leases(&mut self, now: u64) -> Result<bool, EntryManagerError> {
+        let mut lapsed = self
+            .cache
+            .leases
+            .iter()
+            .filter(|(_, x)| x.lapsed(now))
+            .map(|(id, _)| id.clone())
+            .collect::<HashSet<_>>();
+        lapsed.extend(self.live_leaves().into_iter().filter_map(|x| match x {
+            Lifetime::Lease(id) if !self.cache.leases.contains_key(&id) => Some(id),
+            _ => None,
+        }));
+        for id in &lapsed {
+            log::info!("Lease {} has lapsed! Removing associated entries...", id);
+            self.end_lease(id)?;
+        }
+        self.save_cache()?;
+        Ok(!lapsed.is_empty())
+    }
+
+    /// records that the daemon was running at `now`, so leases only run down while it is
+    pub fn checkpoint_leases(&mut self, now: u64) -> Result<(), EntryManagerError> {
+        if !self.cache.leases.is_empty() {
+            self.cache.alive_at = now;
+            self.pending.push(Change::AliveAt(now));
+        }
+        self.save_cache()
+    }
+
+    /// extends every lease by the time the daemon wasn't running before `now`, since their
+    /// clients couldn't renew them in the meantime
+    fn resume_leases(&mut self, now: u64) -> Result<(), EntryManagerError> {
+        let downtime = match self.cache.alive_at {
+            0 => 0,
+            alive_at => now.saturating_sub(alive_at),
+        };
+        if downtime > 0 && !self.cache.leases.is_empty() {
+            log::info!(
+                "The daemon wasn't running for {} seconds, extending leases by as much",
+                downtime
+            );
+            for (id, lease) in self.cache.leases.iter_mut() {
+                lease.expires = lease.expires.saturating_add(downtime);
+                self.pending
+                    .push(Change::Lease(id.clone(), Some(lease.clone())));
+            }
+        }
+        self.checkpoint_leases(now)
+    }
+
+    /// when each lease expires unless it's renewed, in seconds since the unix epoch
+    pub fn lease_expirations(&self) -> HashMap<String, u64> {
+        self.cache
+            .leases
+            .iter()
+            .map(|(id, x)| (id.clone(), x.expires))
+            .collect()
+    }
+
+    /// checks that the leases `lifetime` is made of haven't lapsed or been released
+    fn check_leases(&self, lifetime: &Lifetime) -> Result<(), EntryManagerError> {
+        for leaf in lifetime.leaves() {
+            if let Lifetime::Lease(id) = leaf {
+                if !self.cache.leases.contains_key(id) {
+                    return Err(EntryManagerError::UnknownLease(id.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// forgets the lease `id` and ends its lifetime
+    fn end_lease(&mut self, id: &str) -> Result<(), EntryManagerError> {
+        if self.cache.leases.remove(id).is_some() {
+            self.pending.push(Change::Lease(id.to_string(), None));
+        }
+        self.end_lifetime(Lifetime::Lease(id.to_string()))
+    }
+
+    /// moves everything registered with `from` to `to`, e.g. to keep what an installer
+    /// registered for itself once the installation succeeded. returns the paths of the moved
+    /// files
+    pub fn promote_lifetime(
+        &mut self,
+        from: &Lifetime,
+        to: &Lifetime,
+    ) -> Result<Vec<PathBuf>, EntryManagerError> {
+        if !self.cache.lifetimes().contains(from) {
+            return Err(EntryManagerError::UnknownOwner(format!("{:?}", from)));
+        }
+        self.check_leases(to)?;
+        if from == to {
+            return Ok(Vec::new());
+        }
+        log::info!("Moving {:?} to {:?}", from, to);
+        let paths = self.move_lifetime(from, to, &|_| true)?;
+        self.save_cache()?;
+        Ok(paths)
+    }
+
+    /// moves the registered entry `appid` to `to`, along with its d-bus service and the mime
+    /// associations that point to it. returns the lifetime it had, and the paths of the moved
+    /// files
+    pub fn transfer_entry(
+        &mut self,
+        appid: &str,
+        to: &Lifetime,
+    ) -> Result<(Lifetime, Vec<PathBuf>), EntryManagerError> {
+        let (from, handle) = self
+            .entry_for_appid(appid)
+            .ok_or(EntryManagerError::UnknownAppID(appid.to_string()))?;
+        self.check_leases(to)?;
+        if from == *to {
+            return Ok((from, Vec::new()));
+        }
+        log::info!("Moving {:?} from {:?} to {:?}", appid, from, to);
+        let desktop_file = handle.desktop_file_id();
+        let paths = self.move_lifetime(&from, to, &|x| match x {
+            Record::Entry(x) => x.appid == appid,
+            Record::Resource(x) => x.resource_type == ResourceType::DBusService && x.name == appid,
+            Record::Association(x) => x.desktop_file == desktop_file,
+            Record::Icon(_) => false,
+        })?;
+        self.save_cache()?;
+        Ok((from, paths))
+    }
+
+    /// replaces the lifetime `old`, which isn't a composition, with `new` in every lifetime
+    /// that's made of it
+    pub fn relabel_leaf(
+        &mut self,
+        old: &Lifetime,
+        new: &Lifetime,
+    ) -> Result<(), EntryManagerError> {
+        for lifetime in self.cache.lifetimes() {
+            if !lifetime.leaves().contains(&old) {
+                continue;
+            }
+            let relabeled =
+                lifetime.map_leaves(&|x| if x == old { new.clone() } else { x.clone() });
+            self.move_lifetime(&lifetime, &relabeled, &|_| true)?;
+        }
+        Ok(())
+    }
+
+    /// records that `leaf`, which isn't a composition, has ended, and removes every lifetime
+    /// that has ended with it
+    pub fn end_lifetime(&mut self, leaf: Lifetime) -> Result<(), EntryManagerError> {
+        self.ended.insert(leaf);
+        for lifetime in self.cache.lifetimes() {
+            if lifetime.ended(&self.ended) {
+                self.remove_lifetime(lifetime)?;
+            }
+        }
+        // what's left can only end with lifetimes that are still in use
+        let leaves = self.cache.leaves();
+        self.ended.retain(|x| leaves.contains(x));
+        Ok(())
+    }
+
+    /// every lifetime that isn't a composition that resources depend on, and hasn't ended
+    pub fn live_leaves(&self) -> Vec<Lifetime> {
+        self.cache
+            .leaves()
+            .into_iter()
+            .filter(|x| !self.ended.contains(x))
+            .collect()
+    }
+
+    /// moves what's registered with the lifetime `old` and matches `filter` to `new`, and
+    /// updates the provenance recorded with each file. files are moved into the directories
+    /// for `new` if they differ from those for `old`, and shared files into those of their
+    /// most durable claim. nothing is moved if any of the files can't be, and if moving one
+    /// fails partway, the files and the cache are put back as they were. returns the paths
+    /// of the moved files
+    fn move_lifetime(
+        &mut self,
+        old: &Lifetime,
+        new: &Lifetime,
+        filter: &dyn Fn(&Record) -> bool,
+    ) -> Result<Vec<PathBuf>, EntryManagerError> {
+        let records = self
+            .records(old)
+            .into_iter()
+            .filter(|x| filter(x))
+            .collect::<Vec<_>>();
+        for record in &records {
+            if let Some((path, resource_type)) = record_path(record) {
+                let claims = self.claims_on(old, record);
+                let target = self.relocate(
+                    path,
+                    primary_claim(&claims),
+                    primary_claim(&replace_claim(&claims, old, new)),
+                    resource_type,
+                );
+                if target != path && (target.exists() || target.is_symlink()) {
+                    return Err(EntryManagerError::PathCollision(target));
+                }
+                if resource_type == ResourceType::Autostart
+                    && new.outlives_session()
+                    && self.config.exec_policy != ExecPolicy::Permissive
+                {
+                    return Err(EntryManagerError::PolicyDenied(
+                        "autostart entries outliving the session are not allowed by the exec policy"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+        let mut snapshot = FileSnapshot::default();
+        let mut touched = vec![old.clone(), new.clone()];
+        for record in &records {
+            let Some((path, resource_type)) = record_path(record) else {
+                continue;
+            };
+            let claims = self.claims_on(old, record);
+            let moved = replace_claim(&claims, old, new);
+            let target = self.relocate(
+                path,
+                primary_claim(&claims),
+                primary_claim(&moved),
+                resource_type,
+            );
+            for path in [path, &target] {
+                snapshot.save(path)?;
+                snapshot.save(&sidecar_path(path))?;
+            }
+            if let Record::Entry(DesktopHandle {
+                link: Some(link), ..
+            })
+            | Record::Icon(IconHandle {
+                link: Some(link), ..
+            }) = record
+            {
+                snapshot.save(link)?;
+            }
+            touched.push(primary_claim(&claims).clone());
+            touched.push(primary_claim(&moved).clone());
+        }
+        let cache = self.cache.clone();
+        let pending = self.pending.clone();
+        let app_index = self.app_index.clone();
+        let bus_reload_pending = self.bus_reload_pending;
+        let mime_moved = records.iter().any(
+            |x| matches!(x, Record::Resource(x) if x.resource_type == ResourceType::MimePackage),
+        );
+        match self.move_records(old, new, records) {
+            Ok(paths) => Ok(paths),
+            Err(e) => {
+                log::warn!(
+                    "could not move {:?} to {:?}, putting everything back: {:?}",
+                    old,
+                    new,
+                    e
+                );
+                snapshot.restore();
+                self.cache = cache;
+                self.pending = pending;
+                self.app_index = app_index;
+                self.bus_reload_pending = bus_reload_pending;
+                // these are generated from what's registered, which is as it was again
+                touched.dedup();
+                for lifetime in touched {
+                    let _ = self.write_icon_theme_index(lifetime.clone());
+                    let _ = self.write_mimeapps_list(lifetime.clone());
+                    if mime_moved {
+                        self.refresh_mime_database(lifetime);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// moves `records`, registered with `old`, to `new` for `move_lifetime`, which checked
+    /// that they can be moved
+    fn move_records(
+        &mut self,
+        old: &Lifetime,
+        new: &Lifetime,
+        records: Vec<Record>,
+    ) -> Result<Vec<PathBuf>, EntryManagerError> {
+        let mut paths = Vec::new();
+        for record in records {
+            let claims = replace_claim(&self.claims_on(old, &record), old, new);
+            match record {
+                Record::Entry(handle) => {
+                    let index = self
+                        .cache
+                        .entries
+                        .iter()
+                        .position(|x| x.handle == handle)
+                        .ok_or(EntryManagerError::NotRegistered(handle.path))?;
+                    paths.extend(self.set_entry_claims(index, claims)?);
+                }
+                Record::Icon(handle) => {
+                    let index = self
+                        .cache
+                        .icons
+                        .iter()
+                        .position(|x| x.handle == handle)
+                        .ok_or(EntryManagerError::NotRegistered(handle.icon_path))?;
+                    paths.extend(self.set_icon_claims(index, claims)?);
+                }
+                Record::Resource(handle) => {
+                    let index = self
+                        .cache
+                        .resources
+                        .iter()
+                        .position(|x| x.handle == handle)
+                        .ok_or(EntryManagerError::NotRegistered(handle.path))?;
+                    paths.extend(self.set_resource_claims(index, claims)?);
+                }
+                Record::Association(association) => {
+                    let index = self
+                        .cache
+                        .associations
+                        .iter()
+                        .position(|x| x.handle == association)
+                        .ok_or(EntryManagerError::NotRegistered(PathBuf::from(
+                            association.desktop_file,
+                        )))?;
+                    self.set_association_claims(index, claims)?;
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    /// everything registered with or claimed by `lifetime`
+    fn records(&self, lifetime: &Lifetime) -> Vec<Record> {
+        let mut records = Vec::new();
+        records.extend(
+            self.cache
+                .entries
+                .iter()
+                .filter(|x| x.claims.contains(lifetime))
+                .map(|x| Record::Entry(x.handle.clone())),
+        );
+        records.extend(
+            self.cache
+                .icons
+                .iter()
+                .filter(|x| x.claims.contains(lifetime))
+                .map(|x| Record::Icon(x.handle.clone())),
+        );
+        records.extend(
+            self.cache
+                .resources
+                .iter()
+                .filter(|x| x.claims.contains(lifetime))
+                .map(|x| Record::Resource(x.handle.clone())),
+        );
+        records.extend(
+            self.cache
+                .associations
+                .iter()
+                .filter(|x| x.claims.contains(lifetime))
+                .map(|x| Record::Association(x.handle.clone())),
+        );
+        records
+    }
+
+    /// every lifetime claiming `record`, which is registered with `lifetime`
+    fn claims_on(&self, lifetime: &Lifetime, record: &Record) -> Vec<Lifetime> {
+        let claims = match record {
+            Record::Entry(handle) => self
+                .cache
+                .entries
+                .iter()
+                .find(|x| x.handle == *handle)
+                .map(|x| x.claims.clone()),
+            Record::Icon(handle) => self
+                .cache
+                .icons
+                .iter()
+                .find(|x| x.handle == *handle)
+                .map(|x| x.claims.clone()),
+            Record::Resource(handle) => self
+                .cache
+                .resources
+                .iter()
+                .find(|x| x.handle == *handle)
+                .map(|x| x.claims.clone()),
+            Record::Association(association) => self
+                .cache
+                .associations
+                .iter()
+                .find(|x| x.handle == *association)
+                .map(|x| x.claims.clone()),
+        };
+        claims.unwrap_or_else(|| vec![lifetime.clone()])
+    }
+
+    /// where the file at `path`, registered with `old`, belongs once it's registered with
+    /// `new` instead
+    fn relocate(
+        &self,
+        path: &Path,
+        old: &Lifetime,
+        new: &Lifetime,
+        resource_type: ResourceType,
+    ) -> PathBuf {
+        match path.strip_prefix(self.directory_for_lifetime(old.clone(), resource_type)) {
+            Ok(relative) => self
+                .directory_for_lifetime(new.clone(), resource_type)
+                .join(relative),
+            Err(_) => path.to_owned(),
+        }
+    }
+
+    /// ends every session lifetime, as the session they belong to has ended
+    pub fn reset_session(&mut self) -> Result<(), EntryManagerError> {
+        for leaf in self
+            .live_leaves()
+            .into_iter()
+            .filter(|x| matches!(x, Lifetime::Session(_)))
+        {
+            self.end_lifetime(leaf)?;
+        }
+        self.save_cache()?;
+        Ok(())
+    }
+
+    /// stores the changes made to the cache since it was last saved
+    pub fn save_cache(&mut self) -> Result<(), EntryManagerError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let changes = std::mem::take(&mut self.pending);
+        if let Err(e) = self.storage.commit(&self.cache, &changes) {
+            // the stored cache may be missing any of the changes now
+            self.pending.push(Change::All);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DesktopHandle {
+    pub appid: String,
+    pub path: PathBuf,
+    /// symlink to `path` in the user's data directory, if the entry was exported there
+    #[serde(default)]
+    pub link: Option<PathBuf>,
+}
+
+impl Hash for DesktopHandle {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.appid.hash(state);
+    }
+}
+
+impl DesktopHandle {
+    /// a handle for the entry registered as `appid` and saved at `path`. the file name may
+    /// differ from the appid if the entry was namespaced
+    pub fn new(appid: &str, path: PathBuf) -> Self {
+        Self {
+            appid: appid.to_string(),
+            path,
+            link: None,
+        }
+    }
+
+    /// the desktop file id the entry is saved under
+    pub fn desktop_file_id(&self) -> String {
+        self.path.file_name().unwrap().to_string_lossy().to_string()
+    }
+
+    fn delete_self(self) -> Result<(), std::io::Error> {
+        if let Some(link) = &self.link {
+            remove_link(link, &self.path)?;
+        }
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// removes `link` if it is still a symlink to `target`
+fn remove_link(link: &Path, target: &Path) -> Result<(), std::io::Error> {
+    if fs::read_link(link).is_ok_and(|x| x == target) {
+        fs::remove_file(link)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IconHandle {
+    pub icon_name: String,
+    pub icon_path: PathBuf,
+    /// symlink to `icon_path` in the user's data directory, if the icon was exported there
+    #[serde(default)]
+    pub link: Option<PathBuf>,
+}
+
+impl Hash for IconHandle {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.icon_name.hash(state);
+    }
+}
+
+impl From<PathBuf> for IconHandle {
+    fn from(value: PathBuf) -> Self {
+        Self {
+            icon_name: value.file_stem().unwrap().to_str().unwrap().to_string(),
+            icon_path: value,
+            link: None,
+        }
+    }
+}
+
+impl IconHandle {
+    fn delete_self(self) -> Result<(), std::io::Error> {
+        if let Some(link) = &self.link {
+            remove_link(link, &self.icon_path)?;
+        }
+        fs::remove_file(&self.icon_path)?;
+        remove_sidecar(&self.icon_path)?;
+        Ok(())
+    }
+}
+
+/// a file registered for a resource type that has no dedicated handle type
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceHandle {
+    pub resource_type: ResourceType,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Hash for ResourceHandle {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.resource_type.hash(state);
+        self.name.hash(state);
+    }
+}
+
+impl ResourceHandle {
+    fn delete_self(self) -> Result<(), std::io::Error> {
+        fs::remove_file(&self.path)?;
+        remove_sidecar(&self.path)?;
+        Ok(())
+    }
+}
+
+/// a default application association set through the daemon, stored in the
+/// `[Default Applications]` group of a lifetime's `mimeapps.list`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct MimeAssociation {
+    pub mime_type: String,
+    pub desktop_file: String,
+}
+
+const SIDECAR_EXTENSION: &str = ".provenance";
+
+/// what the daemon records in a sidecar file next to a registered file that can't carry
+/// provenance keys itself, so the cache can be rebuilt from the files on disk
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Sidecar {
+    lifetime: Lifetime,
+    name: String,
+    /// every lifetime holding a claim on the file, if several do
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    claims: Vec<Lifetime>,
+}
+
+impl Sidecar {
+    /// every lifetime holding a claim on the file
+    fn claims(self) -> Vec<Lifetime> {
+        if self.claims.is_empty() {
+            vec![self.lifetime]
+        } else {
+            self.claims
+        }
+    }
+}
+
+/// the sidecar file of the registered file at `path`
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(SIDECAR_EXTENSION);
+    PathBuf::from(sidecar)
+}
+
+/// records that the file at `path` was registered as `name` with `lifetime`
+fn write_sidecar(path: &Path, lifetime: &Lifetime, name: &str) -> Result<(), EntryManagerError> {
+    write_claims_sidecar(path, std::slice::from_ref(lifetime), name)
+}
+
+/// records that the file at `path` was registered as `name`, and is claimed by `claims`
+fn write_claims_sidecar(
+    path: &Path,
+    claims: &[Lifetime],
+    name: &str,
+) -> Result<(), EntryManagerError> {
+    let sidecar = Sidecar {
+        lifetime: primary_claim(claims).clone(),
+        name: name.to_string(),
+        claims: if claims.len() > 1 {
+            claims.to_vec()
+        } else {
+            Vec::new()
+        },
+    };
+    fs::write(sidecar_path(path), ron::to_string(&sidecar)?)?;
+    Ok(())
+}
+
+/// the sidecar of the registered file at `path`, if it has a readable one
+fn read_sidecar(path: &Path) -> Option<Sidecar> {
+    ron::from_str(&fs::read_to_string(sidecar_path(path)).ok()?).ok()
+}
+
+/// removes the sidecar of the registered file at `path`, if it has one
+fn remove_sidecar(path: &Path) -> Result<(), std::io::Error> {
+    match fs::remove_file(sidecar_path(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// the file a record keeps on disk and its type, if it has one of its own
+fn record_path(record: &Record) -> Option<(&Path, ResourceType)> {
+    match record {
+        Record::Entry(x) => Some((&x.path, ResourceType::DesktopEntry)),
+        Record::Icon(x) => Some((&x.icon_path, ResourceType::Icon)),
+        Record::Resource(x) => Some((&x.path, x.resource_type)),
+        Record::Association(_) => None,
+    }
+}
+
+/// files as they were before an operation changed them, so they can be put back if it fails
+#[derive(Default)]
+struct FileSnapshot(Vec<(PathBuf, Option<SavedFile>)>);
+
+/// what was at a path: a file's contents, or where a symlink pointed
+enum SavedFile {
+    Contents(Vec<u8>),
+    Link(PathBuf),
+}
+
+impl FileSnapshot {
+    /// remembers what's at `path` now, or that nothing is
+    fn save(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        if self.0.iter().any(|(x, _)| x == path) {
+            return Ok(());
+        }
+        let saved = match fs::symlink_metadata(path) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+                ) =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
+            Ok(metadata) if metadata.is_symlink() => Some(SavedFile::Link(fs::read_link(path)?)),
+            Ok(_) => Some(SavedFile::Contents(fs::read(path)?)),
+        };
+        self.0.push((path.to_owned(), saved));
+        Ok(())
+    }
+
+    /// puts back what was at each saved path. this carries on past errors, so as much as
+    /// possible is put back
+    fn restore(self) {
+        for (path, saved) in self.0.into_iter().rev() {
+            let result = match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => match saved {
+                    None => Ok(()),
+                    Some(SavedFile::Contents(contents)) => create_dir_all(path.parent().unwrap())
+                        .and_then(|_| write_atomic(&path, &contents)),
+                    Some(SavedFile::Link(target)) => symlink(target, &path),
+                },
+            };
+            if let Err(e) = result {
+                log::error!("could not put back {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+/// writes `contents` to `path` through a temporary file in the same directory, so readers
+/// see either the old file or the new one
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let dir = path.parent().unwrap();
+    create_dir_all(dir)?;
+    let temporary = dir.join(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+/// moves the file at `from` to `to`. files on different filesystems are copied over
+/// atomically before the original is removed
+fn move_file(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    create_dir_all(to.parent().unwrap())?;
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            write_atomic(to, &fs::read(from)?)?;
+            fs::remove_file(from)
+        }
+        x => x,
+    }
+}
+
+/// every file under `dir` and its subdirectories, without following symlinks
+fn walk(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(read_dir) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in read_dir.flatten() {
+            match entry.file_type() {
+                Ok(x) if x.is_dir() => dirs.push(entry.path()),
+                Ok(x) if x.is_file() => files.push(entry.path()),
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desktop_file::DesktopFile;
+    use crate::test_support::{entry_manager, TempDir, ENTRY};
+
+    #[test]
+    fn failed_moves_put_everything_back() {
+        let dir = TempDir::new();
+        let config = Config {
+            exec_policy: ExecPolicy::Permissive,
+            ..Default::default()
+        };
+        let mut manager = entry_manager(dir.path(), config);
+        let session = Lifetime::Session("me".to_string());
+        let persistent = Lifetime::Persistent("me".to_string());
+        manager
+            .register_entry(ENTRY, "com.example.Foo", session.clone())
+            .unwrap();
+        manager
+            .register_autostart(ENTRY, "com.example.Bar", session.clone())
+            .unwrap();
+        let (_, entry) = manager.entry_for_appid("com.example.Foo").unwrap();
+        let autostart = manager.cache.resources[0].handle.path.clone();
+        let files = [&entry.path, &autostart].map(|x| fs::read(x).unwrap());
+        let cache = manager.cache.clone();
+
+        // the entry is moved before the autostart entry, which can't be
+        fs::write(dir.path().join("autostart"), "").unwrap();
+        assert!(manager.promote_lifetime(&session, &persistent).is_err());
+        assert_eq!(manager.cache, cache);
+        assert_eq!(
+            [&entry.path, &autostart].map(|x| fs::read(x).unwrap()),
+            files
+        );
+        assert!(!dir
+            .path()
+            .join("persistent/applications")
+            .join(entry.path.file_name().unwrap())
+            .exists());
+
+        fs::remove_file(dir.path().join("autostart")).unwrap();
+        manager.promote_lifetime(&session, &persistent).unwrap();
+        assert_eq!(
+            manager.entry_for_appid("com.example.Foo").unwrap().0,
+            persistent
+        );
+        assert!(!entry.path.exists() && !autostart.exists());
+    }
+
+    #[test]
+    fn exported_links_are_not_mistaken_for_user_entries() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        manager.symlink_exports = true;
+        manager
+            .register_entry(
+                ENTRY,
+                "com.example.Foo",
+                Lifetime::Session("me".to_string()),
+            )
+            .unwrap();
+        let (_, handle) = manager.entry_for_appid("com.example.Foo").unwrap();
+        let link = handle.link.unwrap();
+        assert!(link.starts_with(&manager.data_home));
+        // the user's data directory is indexed along with the lifetime directories
+        manager.app_index = AppIndex::new(vec![
+            manager.data_home.join("applications"),
+            manager.session_dir.join("applications"),
+        ]);
+        assert_eq!(manager.app_index.paths("com.example.Foo").len(), 2);
+
+        // other owners share the entry rather than getting a namespaced copy of their own
+        manager
+            .register_entry(
+                ENTRY,
+                "com.example.Foo",
+                Lifetime::Session("you".to_string()),
+            )
+            .unwrap();
+        assert_eq!(manager.cache.entries.len(), 1);
+        assert_eq!(manager.cache.entries[0].claims.len(), 2);
+    }
+
+    #[test]
+    fn rebuilding_keeps_the_app_id_of_namespaced_entries() {
+        let dir = TempDir::new();
+        let mut config = Config::default();
+        config.duplicate_policy.other_owner = DuplicateAction::Namespace;
+        let mut manager = entry_manager(dir.path(), config);
+        manager.app_index = AppIndex::new(vec![manager.session_dir.join("applications")]);
+        manager
+            .set_namespace_mode("you", NamespaceMode::Prefix)
+            .unwrap();
+        for owner in ["me", "you"] {
+            manager
+                .register_entry(
+                    ENTRY,
+                    "com.example.Foo",
+                    Lifetime::Session(owner.to_string()),
+                )
+                .unwrap();
+        }
+        let mut entries = manager.cache.entries.clone();
+        assert_ne!(entries[0].handle.path, entries[1].handle.path);
+
+        manager.rebuild_cache().unwrap();
+        let mut rebuilt = manager.cache.entries.clone();
+        let path = |x: &Claimed<DesktopHandle>| x.handle.path.clone();
+        entries.sort_by_key(path);
+        rebuilt.sort_by_key(path);
+        assert_eq!(rebuilt, entries);
+    }
+
+    #[test]
+    fn deadlines_can_only_be_extended_into_the_future() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let now = unix_now();
+        let expiring = |deadline| Lifetime::Expiring {
+            owner: "me".to_string(),
+            deadline,
+        };
+        manager
+            .register_entry(ENTRY, "com.example.Foo", expiring(now + 100))
+            .unwrap();
+        for deadline in [now - 1, now, now + 50] {
+            assert!(manager.extend_deadline("me", deadline).is_err());
+        }
+        manager.extend_deadline("me", now + 200).unwrap();
+        let (lifetime, _) = manager.entry_for_appid("com.example.Foo").unwrap();
+        assert_eq!(lifetime, expiring(now + 200));
+    }
+
+    #[test]
+    fn lease_tokens_are_never_recorded() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let (id, token) = manager.new_lease(60, 0).unwrap();
+        assert_eq!(id, Lease::id(&token));
+        manager
+            .register_entry(ENTRY, "com.example.Foo", Lifetime::Lease(id.clone()))
+            .unwrap();
+        let (lifetime, entry) = manager.entry_for_appid("com.example.Foo").unwrap();
+        assert_eq!(lifetime.owner(), None);
+        assert!(manager.lease_expirations().contains_key(&id));
+        for file in [entry.path, dir.path().join("config/cache.ron")] {
+            assert!(!fs::read_to_string(file).unwrap().contains(&token));
+        }
+
+        // the id is public, so it can't be used to keep the lease alive or end it
+        assert!(manager.renew_lease(&id).is_err());
+        assert!(manager.release_lease(&id).is_err());
+        manager.renew_lease(&token).unwrap();
+        manager.release_lease(&token).unwrap();
+        assert!(manager.entry_for_appid("com.example.Foo").is_none());
+    }
+
+    #[test]
+    fn services_and_associations_outlive_the_primary_claim() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let session = Lifetime::Session("me".to_string());
+        let persistent = Lifetime::Persistent("you".to_string());
+        manager
+            .register_entry(ENTRY, "com.example.Foo", session.clone())
+            .unwrap();
+        manager
+            .register_dbus_service(
+                "[D-BUS Service]\nName=com.example.Foo\nExec=foo\n",
+                "com.example.Foo",
+            )
+            .unwrap();
+        manager
+            .set_default_application("text/plain", "com.example.Foo")
+            .unwrap();
+        // the persistent claim becomes the primary one, and everything moves along with it
+        manager
+            .register_entry(ENTRY, "com.example.Foo", persistent.clone())
+            .unwrap();
+        let service = |root: &str| {
+            dir.path()
+                .join(root)
+                .join("dbus-1/services/com.example.Foo.service")
+        };
+        let mimeapps_list = |root: &str| dir.path().join(root).join("applications/mimeapps.list");
+        assert!(service("persistent").exists() && !service("session").exists());
+        assert!(mimeapps_list("persistent").exists() && !mimeapps_list("session").exists());
+
+        manager.remove_lifetime(persistent).unwrap();
+        assert!(manager.entry_for_appid("com.example.Foo").is_some());
+        assert!(service("session").exists() && !service("persistent").exists());
+        assert!(fs::read_to_string(mimeapps_list("session"))
+            .unwrap()
+            .contains("text/plain=com.example.Foo.desktop;"));
+        assert!(!mimeapps_list("persistent").exists());
+        assert_eq!(
+            manager.default_application("text/plain").as_deref(),
+            Some("com.example.Foo.desktop")
+        );
+
+        manager.remove_lifetime(session).unwrap();
+        assert!(!service("session").exists() && !mimeapps_list("session").exists());
+        assert!(manager.cache.resources.is_empty() && manager.cache.associations.is_empty());
+    }
+
+    #[test]
+    fn lifetimes_without_an_owner_are_namespaced_by_what_they_are_bound_to() {
+        let dir = TempDir::new();
+        let mut config = Config::default();
+        config.duplicate_policy.other_owner = DuplicateAction::Namespace;
+        let mut manager = entry_manager(dir.path(), config);
+        manager
+            .register_entry(
+                ENTRY,
+                "com.example.Foo",
+                Lifetime::Session("me".to_string()),
+            )
+            .unwrap();
+        let app = dir.path().join("Foo.AppImage");
+        fs::write(&app, "").unwrap();
+        let path = Lifetime::Path {
+            path: app.clone(),
+            remove_on_move: false,
+        };
+        let container = Lifetime::Container {
+            engine: ContainerEngine::Podman,
+            container: "0123abcd".to_string(),
+        };
+        // without a namespace mode chosen for them, duplicates are still refused
+        assert!(manager
+            .register_entry(ENTRY, "com.example.Foo", path.clone())
+            .is_err());
+
+        manager
+            .set_namespace_mode(app.to_str().unwrap(), NamespaceMode::Prefix)
+            .unwrap();
+        manager
+            .set_namespace_mode("0123abcd", NamespaceMode::Suffix)
+            .unwrap();
+        for lifetime in [path, container] {
+            manager
+                .register_entry(ENTRY, "com.example.Foo", lifetime)
+                .unwrap();
+        }
+        let namespaced = app
+            .to_str()
+            .unwrap()
+            .trim_start_matches('/')
+            .replace(['/', '.'], "-");
+        for file in [
+            format!("{}.com.example.Foo.desktop", namespaced),
+            "com.example.Foo.0123abcd.desktop".to_string(),
+        ] {
+            assert!(dir
+                .path()
+                .join("persistent/applications")
+                .join(file)
+                .exists());
+        }
+    }
+
+    #[test]
+    fn autostart_app_ids_cannot_leave_the_autostart_directory() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let session = Lifetime::Session("me".to_string());
+        for appid in ["../evil", "nested/../../evil", "..", ".hidden", ""] {
+            assert!(manager
+                .register_autostart(ENTRY, appid, session.clone())
+                .is_err());
+        }
+        assert!(!dir.path().join("session/config/evil.desktop").exists());
+        assert!(!dir.path().join("evil.desktop").exists());
+        assert!(manager.cache.resources.is_empty());
+        manager
+            .register_autostart(ENTRY, "com.example.Foo", session)
+            .unwrap();
+    }
+
+    #[test]
+    fn version_1_caches_are_migrated_and_backed_up() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.ron");
+        let v1 = r#"(
+    version: 1,
+    entries: {
+        Session("a"): [(appid: "com.example.Foo", path: "/apps/com.example.Foo.desktop")],
+    },
+    icons: {
+        Process(1): [(icon_name: "foo", icon_path: "/icons/foo.png")],
+    },
+    resources: {
+        Persistent("b"): [
+            (resource_type: DBusService, name: "com.example.Foo", path: "/services/foo.service"),
+        ],
+    },
+    associations: {
+        Persistent("b"): [(mime_type: "text/plain", desktop_file: "com.example.Foo.desktop")],
+    },
+    namespace_modes: {"a": Prefix},
+    session: Some("1"),
+)"#;
+        fs::write(&path, v1).unwrap();
+        let persistent = Lifetime::Persistent("b".to_string());
+        let expected = EntryCache {
+            entries: vec![Claimed::new(
+                DesktopHandle {
+                    appid: "com.example.Foo".to_string(),
+                    path: PathBuf::from("/apps/com.example.Foo.desktop"),
+                    link: None,
+                },
+                Lifetime::Session("a".to_string()),
+            )],
+            icons: vec![Claimed::new(
+                IconHandle {
+                    icon_name: "foo".to_string(),
+                    icon_path: PathBuf::from("/icons/foo.png"),
+                    link: None,
+                },
+                Lifetime::Process(1),
+            )],
+            resources: vec![Claimed::new(
+                ResourceHandle {
+                    resource_type: ResourceType::DBusService,
+                    name: "com.example.Foo".to_string(),
+                    path: PathBuf::from("/services/foo.service"),
+                },
+                persistent.clone(),
+            )],
+            associations: vec![Claimed::new(
+                MimeAssociation {
+                    mime_type: "text/plain".to_string(),
+                    desktop_file: "com.example.Foo.desktop".to_string(),
+                },
+                persistent,
+            )],
+            namespace_modes: [("a".to_string(), NamespaceMode::Prefix)].into(),
+            session: Some("1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(EntryCache::new(&path).unwrap(), expected);
+        assert_eq!(
+            fs::read_to_string(cache_backup_path(&path, "v1")).unwrap(),
+            v1
+        );
+        // the migrated cache is written back, so it's only migrated once
+        let migrated: EntryCache = ron::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(migrated, expected);
+        assert_eq!(migrated.version, CACHE_VERSION);
+    }
+
+    #[test]
+    fn nested_compositions_end_with_their_members() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let session = Lifetime::Session("me".to_string());
+        let unit = Lifetime::Unit("foo.service".to_string());
+        let lifetimes = [
+            (
+                "com.example.Foo",
+                Lifetime::AnyOf(vec![
+                    Lifetime::Process(1),
+                    Lifetime::AllOf(vec![session.clone(), unit.clone()]),
+                ]),
+            ),
+            (
+                "com.example.Bar",
+                Lifetime::AllOf(vec![
+                    Lifetime::AnyOf(vec![Lifetime::Process(2), unit.clone()]),
+                    session.clone(),
+                ]),
+            ),
+            (
+                "com.example.Baz",
+                Lifetime::AnyOf(vec![
+                    Lifetime::Process(3),
+                    Lifetime::AllOf(vec![session.clone(), Lifetime::Process(4)]),
+                ]),
+            ),
+        ];
+        let mut paths = Vec::new();
+        for (appid, lifetime) in lifetimes {
+            manager.register_entry(ENTRY, appid, lifetime).unwrap();
+            paths.push(manager.entry_for_appid(appid).unwrap().1.path);
+        }
+        let registered = |manager: &EntryManager| {
+            ["com.example.Foo", "com.example.Bar", "com.example.Baz"]
+                .into_iter()
+                .filter(|x| manager.entry_for_appid(x).is_some())
+                .collect::<Vec<_>>()
+        };
+
+        manager.end_lifetime(unit).unwrap();
+        assert_eq!(registered(&manager).len(), 3);
+        manager.end_lifetime(session).unwrap();
+        assert_eq!(registered(&manager), vec!["com.example.Baz"]);
+        assert!(!paths[0].exists() && !paths[1].exists());
+        manager.end_lifetime(Lifetime::Process(3)).unwrap();
+        assert!(registered(&manager).is_empty());
+        assert!(!paths[2].exists());
+        assert!(manager.live_leaves().is_empty());
+    }
+
+    #[test]
+    fn all_of_ends_once_its_last_member_does_in_any_order() {
+        let members = [
+            Lifetime::Process(1),
+            Lifetime::Session("me".to_string()),
+            Lifetime::Unit("foo.service".to_string()),
+        ];
+        for order in [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ] {
+            let dir = TempDir::new();
+            let mut manager = entry_manager(dir.path(), Config::default());
+            manager
+                .register_entry(ENTRY, "com.example.Foo", Lifetime::AllOf(members.to_vec()))
+                .unwrap();
+            let (_, entry) = manager.entry_for_appid("com.example.Foo").unwrap();
+            for (i, member) in order.iter().enumerate() {
+                assert!(entry.path.exists(), "{:?} ended early", order);
+                manager.end_lifetime(members[*member].clone()).unwrap();
+                // members that ended are remembered until the composition does
+                assert_eq!(manager.live_leaves().len(), 2 - i, "{:?}", order);
+            }
+            assert!(manager.entry_for_appid("com.example.Foo").is_none());
+            assert!(!entry.path.exists());
+        }
+    }
+
+    #[async_std::test]
+    async fn mime_databases_are_updated_once_per_directory_after_unlocking() {
+        let dir = TempDir::new();
+        let manager = Mutex::new(entry_manager(dir.path(), Config::default()));
+        let package = |mime_type: &str| {
+            format!(
+                "<mime-info xmlns=\"http://www.freedesktop.org/standards/shared-mime-info\">\
+                 <mime-type type=\"{}\"><glob pattern=\"*.foo\"/></mime-type></mime-info>",
+                mime_type
+            )
+        };
+        {
+            let mut manager = manager.lock().await;
+            let session = Lifetime::Session("me".to_string());
+            for name in ["foo", "bar"] {
+                manager
+                    .register_mime_package(
+                        &package(&format!("application/x-{}", name)),
+                        name,
+                        session.clone(),
+                    )
+                    .unwrap();
+            }
+            assert_eq!(
+                manager.mime_refresh_pending,
+                HashSet::from([dir.path().join("session/mime")])
+            );
+        }
+        update_mime_databases(&manager).await;
+        assert!(manager.lock().await.mime_refresh_pending.is_empty());
+        let globs = fs::read_to_string(dir.path().join("session/mime/globs2")).unwrap();
+        assert!(globs.contains("application/x-foo") && globs.contains("application/x-bar"));
+    }
+
+    #[test]
+    fn icons_are_written_into_their_context_and_listed_in_the_theme_index() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let session = Lifetime::Session("me".to_string());
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="16" height="16"/>"#;
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(16, 16)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        manager
+            .register_icon(
+                "foo",
+                svg.as_bytes(),
+                IconContext::from_str("MimeTypes").unwrap(),
+                session.clone(),
+            )
+            .unwrap();
+        manager
+            .register_icon(
+                "bar",
+                &png,
+                IconContext::from_str("status").unwrap(),
+                session,
+            )
+            .unwrap();
+        assert!(IconContext::from_str("wallpapers").is_err());
+
+        let hicolor = dir.path().join("session/icons/hicolor");
+        assert!(hicolor.join("scalable/mimetypes/foo.svg").exists());
+        assert!(hicolor.join("16x16/status/bar.png").exists());
+        let (index, _) =
+            DesktopFile::parse(&fs::read_to_string(hicolor.join("index.theme")).unwrap());
+        let theme = index.group("Icon Theme").unwrap();
+        assert_eq!(
+            theme.get("Directories").unwrap().value,
+            "16x16/status,scalable/mimetypes"
+        );
+        let context = |group: &str| {
+            index
+                .group(group)
+                .unwrap()
+                .get("Context")
+                .unwrap()
+                .value
+                .clone()
+        };
+        assert_eq!(context("16x16/status"), "Status");
+        assert_eq!(context("scalable/mimetypes"), "MimeTypes");
+        assert_eq!(
+            index
+                .group("scalable/mimetypes")
+                .unwrap()
+                .get("Type")
+                .unwrap()
+                .value,
+            "Scalable"
+        );
+    }
+
+    #[test]
+    fn default_applications_are_written_to_the_mimeapps_list_of_their_entry() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        manager
+            .register_entry(
+                ENTRY,
+                "com.example.Foo",
+                Lifetime::Session("me".to_string()),
+            )
+            .unwrap();
+        manager
+            .register_entry(
+                ENTRY,
+                "com.example.Bar",
+                Lifetime::Persistent("me".to_string()),
+            )
+            .unwrap();
+        let list = |root: &str| {
+            fs::read_to_string(dir.path().join(root).join("applications/mimeapps.list")).ok()
+        };
+
+        manager
+            .set_default_application("text/plain", "com.example.Foo")
+            .unwrap();
+        manager
+            .set_default_application("image/png", "com.example.Foo")
+            .unwrap();
+        manager
+            .set_default_application("text/html", "com.example.Bar")
+            .unwrap();
+        assert_eq!(
+            list("session").unwrap(),
+            "[Default Applications]\nimage/png=com.example.Foo.desktop;\n\
+             text/plain=com.example.Foo.desktop;\n"
+        );
+        assert_eq!(
+            list("persistent").unwrap(),
+            "[Default Applications]\ntext/html=com.example.Bar.desktop;\n"
+        );
+        assert!(manager
+            .set_default_application("text/plain", "com.example.Baz")
+            .is_err());
+        assert!(manager
+            .set_default_application("text", "com.example.Foo")
+            .is_err());
+
+        // setting a type again moves it to the list of the new entry
+        manager
+            .set_default_application("text/plain", "com.example.Bar")
+            .unwrap();
+        assert_eq!(
+            list("session").unwrap(),
+            "[Default Applications]\nimage/png=com.example.Foo.desktop;\n"
+        );
+        assert_eq!(
+            manager.default_application("text/plain").unwrap(),
+            "com.example.Bar.desktop"
+        );
+        manager.remove_default_application("image/png").unwrap();
+        assert_eq!(list("session"), None);
+        assert!(list("persistent")
+            .unwrap()
+            .contains("text/plain=com.example.Bar.desktop;"));
+    }
+
+    #[test]
+    fn dbus_services_are_validated_and_written_next_to_their_entry() {
+        let dir = TempDir::new();
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let session = Lifetime::Session("me".to_string());
+        let service = "[D-BUS Service]\nName=com.example.Foo\nExec=/usr/bin/foo\n";
+        assert!(matches!(
+            manager.register_dbus_service(service, "com.example.Foo"),
+            Err(EntryManagerError::UnknownAppID(_))
+        ));
+        manager
+            .register_entry(
+                &format!("{}DBusActivatable=true\n", ENTRY),
+                "com.example.Foo",
+                session.clone(),
+            )
+            .unwrap();
+        for invalid in [
+            "[D-BUS Service]\nName=com.example.Bar\nExec=/usr/bin/foo\n",
+            "[D-BUS Service]\nName=com.example.Foo\n",
+            "[D-BUS Service]\nnot a key\n",
+        ] {
+            assert!(matches!(
+                manager.register_dbus_service(invalid, "com.example.Foo"),
+                Err(EntryManagerError::ServiceValidation(_))
+            ));
+        }
+        assert!(!manager.bus_reload_pending);
+
+        manager
+            .register_dbus_service(service, "com.example.Foo")
+            .unwrap();
+        let path = dir
+            .path()
+            .join("session/dbus-1/services/com.example.Foo.service");
+        assert_eq!(fs::read_to_string(&path).unwrap(), service);
+        assert!(manager.bus_reload_pending);
+        assert!(matches!(
+            manager.register_dbus_service(service, "com.example.Foo"),
+            Err(EntryManagerError::PathCollision(_))
+        ));
+
+        manager.bus_reload_pending = false;
+        manager.remove_lifetime(session).unwrap();
+        assert!(!path.exists());
+        assert!(manager.bus_reload_pending);
+    }
 }
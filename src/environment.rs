@@ -0,0 +1,214 @@
+use std::{collections::HashMap, env, path::PathBuf};
+
+use zbus::{fdo::DBusProxy, Connection};
+
+use crate::systemd::ManagerProxy;
+
+/// the `XDG_DATA_DIRS` the spec says to assume when the variable is unset or empty
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share/:/usr/share/";
+/// the `XDG_CONFIG_DIRS` the spec says to assume when the variable is unset or empty
+const DEFAULT_CONFIG_DIRS: &str = "/etc/xdg/";
+
+/// returns `data_dirs` with every root in `roots` that it doesn't already contain appended,
+/// or `None` if nothing is missing
+pub fn data_dirs_including(data_dirs: Option<&str>, roots: &[PathBuf]) -> Option<String> {
+    dirs_including(data_dirs, DEFAULT_DATA_DIRS, roots)
+}
+
+/// returns the search path `dirs`, or `default` if it's unset or empty, with every root in
+/// `roots` that it doesn't already contain appended, or `None` if nothing is missing
+fn dirs_including(dirs: Option<&str>, default: &str, roots: &[PathBuf]) -> Option<String> {
+    let mut dirs = dirs
+        .filter(|x| !x.is_empty())
+        .unwrap_or(default)
+        .split(':')
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let mut changed = false;
+    for root in roots {
+        let root = root.to_string_lossy().trim_end_matches('/').to_string();
+        if !dirs.iter().any(|x| x.trim_end_matches('/') == root) {
+            dirs.push(format!("{}/", root));
+            changed = true;
+        }
+    }
+    changed.then(|| dirs.join(":"))
+}
+
+/// the lifetime `roots` that are missing from the session's `XDG_DATA_DIRS`. the session's
+/// value is taken from the systemd user manager, or from the daemon's own environment if
+/// the manager can't be reached
+pub async fn missing_data_dirs(connection: &Connection, roots: &[PathBuf]) -> Vec<PathBuf> {
+    let manager_environment = match ManagerProxy::new(connection).await {
+        Ok(manager) => manager.environment().await.ok(),
+        Err(_) => None,
+    };
+    let data_dirs = match manager_environment {
+        Some(environment) => environment
+            .iter()
+            .find_map(|x| x.strip_prefix("XDG_DATA_DIRS=").map(String::from)),
+        None => env::var("XDG_DATA_DIRS").ok(),
+    };
+    roots
+        .iter()
+        .filter(|root| data_dirs_including(data_dirs.as_deref(), &[(*root).clone()]).is_some())
+        .cloned()
+        .collect()
+}
+
+/// makes sure the lifetime `roots` are part of the `XDG_DATA_DIRS` the systemd user manager
+/// and the d-bus activation environment hand to anything started from now on, so the
+/// managed directories are visible without logging out
+pub async fn export_data_dirs(connection: &Connection, roots: &[PathBuf]) -> zbus::Result<()> {
+    export_dirs(connection, "XDG_DATA_DIRS", DEFAULT_DATA_DIRS, roots).await
+}
+
+/// makes sure the config `roots` holding autostart entries that end with the session are part
+/// of the `XDG_CONFIG_DIRS` handed to anything started from now on, like `export_data_dirs`
+pub async fn export_config_dirs(connection: &Connection, roots: &[PathBuf]) -> zbus::Result<()> {
+    export_dirs(connection, "XDG_CONFIG_DIRS", DEFAULT_CONFIG_DIRS, roots).await
+}
+
+/// adds the `roots` missing from the search path `variable`, which is `default` when unset,
+/// to the systemd user manager and d-bus activation environments
+async fn export_dirs(
+    connection: &Connection,
+    variable: &str,
+    default: &str,
+    roots: &[PathBuf],
+) -> zbus::Result<()> {
+    let Some(dirs) = dirs_including(env::var(variable).ok().as_deref(), default, roots) else {
+        log::debug!("{} already includes the managed directories", variable);
+        return Ok(());
+    };
+    log::info!("Exporting {}={}", variable, dirs);
+    let assignment = format!("{}={}", variable, dirs);
+    if let Err(e) = ManagerProxy::new(connection)
+        .await?
+        .set_environment(&[&assignment])
+        .await
+    {
+        log::warn!("could not update the systemd user environment: {:?}", e);
+    }
+    DBusProxy::new(connection)
+        .await?
+        .update_activation_environment(HashMap::from([(variable, dirs.as_str())]))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use zbus::interface;
+
+    use super::*;
+    use crate::test_support::{PrivateBus, TempDir};
+
+    struct FakeManager {
+        environment: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Manager")]
+    impl FakeManager {
+        fn set_environment(&self, assignments: Vec<String>) {
+            let mut environment = self.environment.lock().unwrap();
+            for assignment in assignments {
+                let name = assignment.split('=').next().unwrap().to_string();
+                environment.retain(|x| !x.starts_with(&format!("{}=", name)));
+                environment.push(assignment);
+            }
+        }
+
+        #[zbus(property)]
+        fn environment(&self) -> Vec<String> {
+            self.environment.lock().unwrap().clone()
+        }
+    }
+
+    /// serves a systemd user manager on `bus` whose environment starts out as `environment`,
+    /// for as long as the returned connection is kept
+    async fn fake_manager(
+        bus: &PrivateBus,
+        environment: &[String],
+    ) -> (Connection, Arc<Mutex<Vec<String>>>) {
+        let environment = Arc::new(Mutex::new(environment.to_vec()));
+        let connection = bus.connect().await;
+        connection
+            .object_server()
+            .at(
+                "/org/freedesktop/systemd1",
+                FakeManager {
+                    environment: environment.clone(),
+                },
+            )
+            .await
+            .unwrap();
+        connection
+            .request_name("org.freedesktop.systemd1")
+            .await
+            .unwrap();
+        (connection, environment)
+    }
+
+    #[test]
+    fn missing_roots_are_appended_to_search_paths() {
+        let roots = [PathBuf::from("/run/a"), PathBuf::from("/run/b/")];
+        assert_eq!(
+            data_dirs_including(None, &roots).unwrap(),
+            "/usr/local/share/:/usr/share/:/run/a/:/run/b/"
+        );
+        assert_eq!(
+            data_dirs_including(Some(""), &roots[..1]).unwrap(),
+            "/usr/local/share/:/usr/share/:/run/a/"
+        );
+        assert_eq!(
+            dirs_including(Some("/opt/share:/run/b"), DEFAULT_CONFIG_DIRS, &roots).unwrap(),
+            "/opt/share:/run/b:/run/a/"
+        );
+        assert_eq!(
+            dirs_including(None, DEFAULT_CONFIG_DIRS, &roots).unwrap(),
+            "/etc/xdg/:/run/a/:/run/b/"
+        );
+        assert_eq!(data_dirs_including(Some("/run/a/:/run/b"), &roots), None);
+    }
+
+    #[async_std::test]
+    async fn missing_roots_are_exported_to_the_user_manager() {
+        let bus = PrivateBus::new();
+        let dir = TempDir::new();
+        let present = dir.path().join("present");
+        let missing = dir.path().join("missing");
+        let (_manager, environment) = fake_manager(
+            &bus,
+            &[format!("XDG_DATA_DIRS=/usr/share/:{}/", present.display())],
+        )
+        .await;
+        let connection = bus.connect().await;
+        assert_eq!(
+            missing_data_dirs(&connection, &[present.clone(), missing.clone()]).await,
+            vec![missing.clone()]
+        );
+
+        export_data_dirs(&connection, std::slice::from_ref(&missing))
+            .await
+            .unwrap();
+        let environment = environment.lock().unwrap().clone();
+        let data_dirs = environment
+            .iter()
+            .find_map(|x| x.strip_prefix("XDG_DATA_DIRS="))
+            .unwrap();
+        assert!(data_dirs.ends_with(&format!(":{}/", missing.display())));
+    }
+
+    #[async_std::test]
+    async fn exporting_without_a_user_manager_still_updates_the_activation_environment() {
+        let bus = PrivateBus::new();
+        let dir = TempDir::new();
+        let connection = bus.connect().await;
+        export_config_dirs(&connection, &[dir.path().join("config")])
+            .await
+            .unwrap();
+    }
+}
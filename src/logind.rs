@@ -0,0 +1,347 @@
+use std::{env, fs, os::unix::fs::MetadataExt};
+
+use async_std::{
+    stream::StreamExt,
+    sync::{Arc, Mutex},
+};
+use zbus::{proxy, zvariant::OwnedObjectPath, Connection, MatchRule, MessageStream};
+
+use crate::entry_management::EntryManager;
+
+/// the parts of logind the daemon talks to, on the system bus
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+pub trait Manager {
+    /// the object path of the user `uid`, if they are logged in or lingering
+    fn get_user(&self, uid: u32) -> zbus::Result<OwnedObjectPath>;
+
+    /// the object path of the session `session_id`, if it exists
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn session_new(&self, session_id: String, object_path: OwnedObjectPath) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn session_removed(&self, session_id: String, object_path: OwnedObjectPath)
+        -> zbus::Result<()>;
+}
+
+/// a user known to logind
+#[proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1"
+)]
+pub trait User {
+    /// the id and path of the user's graphical session, or an empty id if they have none
+    #[zbus(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+/// whether logind knows of the session `session_id`
+pub async fn session_exists(connection: &Connection, session_id: &str) -> zbus::Result<bool> {
+    let manager = ManagerProxy::new(connection).await?;
+    match manager.get_session(session_id).await {
+        Ok(_) => Ok(true),
+        Err(zbus::Error::MethodError(error, _, _))
+            if error.as_str() == "org.freedesktop.login1.NoSuchSession" =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// the login session the daemon runs for: `XDG_SESSION_ID` if the daemon was started in a
+/// session, otherwise the user's graphical session. `None` if the user has no session
+pub async fn current_session(connection: &Connection) -> zbus::Result<Option<String>> {
+    if let Ok(session_id) = env::var("XDG_SESSION_ID") {
+        if session_exists(connection, &session_id).await? {
+            return Ok(Some(session_id));
+        }
+    }
+    let uid = fs::metadata("/proc/self")
+        .map_err(|e| zbus::Error::Failure(e.to_string()))?
+        .uid();
+    let manager = ManagerProxy::new(connection).await?;
+    let user = UserProxy::builder(connection)
+        .path(manager.get_user(uid).await?)?
+        .build()
+        .await?;
+    let (session_id, _) = user.display().await?;
+    Ok(Some(session_id).filter(|x| !x.is_empty()))
+}
+
+/// ends session lifetimes when the login session they belong to is removed from logind. if
+/// there was no session to bind them to, they're bound to the next one that starts
+pub async fn watch_sessions(
+    manager: Arc<Mutex<EntryManager>>,
+    connection: Connection,
+) -> zbus::Result<()> {
+    let rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.login1")?
+        .interface("org.freedesktop.login1.Manager")?
+        .path("/org/freedesktop/login1")?
+        .build();
+    let mut signals = MessageStream::for_match_rule(rule, &connection, None).await?;
+    log::info!("Watching if the login session ends!");
+    while let Some(message) = signals.next().await {
+        let message = message?;
+        let session = manager.lock().await.cache.session.clone();
+        if let Some(removed) = SessionRemoved::from_message(message.clone()) {
+            let session_id = removed.args()?.session_id;
+            if session.as_ref() != Some(&session_id) {
+                continue;
+            }
+            log::info!(
+                "Session {} has ended! Removing session entries...",
+                session_id
+            );
+            // the user may still have another session, i.e. when they log out of one of two
+            let next = current_session(&connection).await.unwrap_or_else(|e| {
+                log::warn!("could not get the login session from logind: {:?}", e);
+                None
+            });
+            let mut manager_lock = manager.lock().await;
+            if manager_lock.reset_session().is_err() {
+                log::error!("Something went wrong when removing the session lifetime");
+            }
+            if manager_lock.set_session(next).is_err() {
+                log::error!("Something went wrong when saving the cache to disk");
+            }
+        } else if SessionNew::from_message(message).is_some() && session.is_none() {
+            let next = current_session(&connection).await?;
+            if next.is_some() {
+                log::info!("Binding session lifetimes to new session {:?}", next);
+            }
+            if manager.lock().await.set_session(next).is_err() {
+                log::error!("Something went wrong when saving the cache to disk");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as SyncMutex;
+
+    use async_std::task;
+    use zbus::{interface, zvariant::ObjectPath, SignalContext};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        entry_management::Lifetime,
+        test_support::{entry_manager, eventually, PrivateBus, TempDir, ENTRY},
+    };
+
+    #[derive(Debug, zbus::DBusError)]
+    #[zbus(prefix = "org.freedesktop.login1")]
+    enum FakeError {
+        #[zbus(error)]
+        ZBus(zbus::Error),
+        NoSuchSession(String),
+    }
+
+    fn session_path(session_id: &str) -> OwnedObjectPath {
+        ObjectPath::try_from(format!("/org/freedesktop/login1/session/_{}", session_id))
+            .unwrap()
+            .into()
+    }
+
+    /// stands in for logind, which knows of the sessions in `sessions`
+    struct FakeManager {
+        sessions: Arc<SyncMutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeManager {
+        fn get_user(&self, uid: u32) -> OwnedObjectPath {
+            ObjectPath::try_from(format!("/org/freedesktop/login1/user/_{}", uid))
+                .unwrap()
+                .into()
+        }
+
+        fn get_session(&self, session_id: String) -> Result<OwnedObjectPath, FakeError> {
+            if !self.sessions.lock().unwrap().contains(&session_id) {
+                return Err(FakeError::NoSuchSession(session_id));
+            }
+            Ok(session_path(&session_id))
+        }
+
+        #[zbus(signal)]
+        async fn session_new(
+            ctxt: &SignalContext<'_>,
+            session_id: &str,
+            object_path: ObjectPath<'_>,
+        ) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn session_removed(
+            ctxt: &SignalContext<'_>,
+            session_id: &str,
+            object_path: ObjectPath<'_>,
+        ) -> zbus::Result<()>;
+    }
+
+    /// the user, whose graphical session is the first in `sessions`
+    struct FakeUser {
+        sessions: Arc<SyncMutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.freedesktop.login1.User")]
+    impl FakeUser {
+        #[zbus(property)]
+        fn display(&self) -> (String, OwnedObjectPath) {
+            match self.sessions.lock().unwrap().first() {
+                Some(session_id) => (session_id.clone(), session_path(session_id)),
+                None => (String::new(), ObjectPath::try_from("/").unwrap().into()),
+            }
+        }
+    }
+
+    /// logind on a private bus, whose sessions the test starts and ends
+    struct FakeLogind {
+        connection: Connection,
+        sessions: Arc<SyncMutex<Vec<String>>>,
+    }
+
+    impl FakeLogind {
+        async fn new(bus: &PrivateBus) -> Self {
+            let connection = bus.connect().await;
+            let sessions = Arc::new(SyncMutex::new(Vec::new()));
+            let uid = fs::metadata("/proc/self").unwrap().uid();
+            let user = FakeUser {
+                sessions: sessions.clone(),
+            };
+            connection
+                .object_server()
+                .at(format!("/org/freedesktop/login1/user/_{}", uid), user)
+                .await
+                .unwrap();
+            let manager = FakeManager {
+                sessions: sessions.clone(),
+            };
+            connection
+                .object_server()
+                .at("/org/freedesktop/login1", manager)
+                .await
+                .unwrap();
+            connection
+                .request_name("org.freedesktop.login1")
+                .await
+                .unwrap();
+            Self {
+                connection,
+                sessions,
+            }
+        }
+
+        fn context(&self) -> SignalContext<'_> {
+            SignalContext::new(&self.connection, "/org/freedesktop/login1").unwrap()
+        }
+
+        async fn start(&self, session_id: &str) {
+            self.sessions.lock().unwrap().push(session_id.to_string());
+            FakeManager::session_new(&self.context(), session_id, session_path(session_id).into())
+                .await
+                .unwrap();
+        }
+
+        async fn end(&self, session_id: &str) {
+            self.sessions.lock().unwrap().retain(|x| x != session_id);
+            FakeManager::session_removed(
+                &self.context(),
+                session_id,
+                session_path(session_id).into(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    /// starts the daemon's entry manager in `dir` as the daemon does, with session lifetimes
+    /// bound to the current session
+    async fn start_daemon(dir: &TempDir, connection: Option<&Connection>) -> EntryManager {
+        let mut manager = entry_manager(dir.path(), Config::default());
+        let session = match connection {
+            Some(connection) => current_session(connection).await.unwrap(),
+            None => None,
+        };
+        manager.bind_session(session).unwrap();
+        manager
+    }
+
+    fn register(manager: &mut EntryManager) {
+        manager
+            .register_entry(
+                ENTRY,
+                "com.example.Foo",
+                Lifetime::Session("me".to_string()),
+            )
+            .unwrap();
+    }
+
+    fn registered(manager: &EntryManager) -> bool {
+        manager.entry_for_appid("com.example.Foo").is_some()
+    }
+
+    #[async_std::test]
+    async fn session_lifetimes_survive_restarts_within_the_session() {
+        let bus = PrivateBus::new();
+        let logind = FakeLogind::new(&bus).await;
+        let connection = bus.connect().await;
+        logind.start("1").await;
+        let dir = TempDir::new();
+        register(&mut start_daemon(&dir, Some(&connection)).await);
+
+        let mut manager = start_daemon(&dir, Some(&connection)).await;
+        assert_eq!(manager.cache.session.as_deref(), Some("1"));
+        assert!(registered(&manager));
+        drop(manager);
+
+        logind.end("1").await;
+        logind.start("2").await;
+        manager = start_daemon(&dir, Some(&connection)).await;
+        assert_eq!(manager.cache.session.as_deref(), Some("2"));
+        assert!(!registered(&manager));
+    }
+
+    #[async_std::test]
+    async fn session_lifetimes_end_on_every_start_without_a_session() {
+        let dir = TempDir::new();
+        register(&mut start_daemon(&dir, None).await);
+        let manager = start_daemon(&dir, None).await;
+        assert_eq!(manager.cache.session, None);
+        assert!(!registered(&manager));
+    }
+
+    #[async_std::test]
+    async fn session_lifetimes_end_when_logind_removes_the_session() {
+        let bus = PrivateBus::new();
+        let logind = FakeLogind::new(&bus).await;
+        let connection = bus.connect().await;
+        logind.start("1").await;
+        logind.start("2").await;
+        let dir = TempDir::new();
+        let mut manager = start_daemon(&dir, Some(&connection)).await;
+        register(&mut manager);
+        let manager = Arc::new(Mutex::new(manager));
+        task::spawn(watch_sessions(manager.clone(), connection));
+
+        // only the session the lifetimes are bound to ends them
+        logind.end("2").await;
+        task::sleep(std::time::Duration::from_secs(1)).await;
+        assert!(registered(&*manager.lock().await));
+
+        logind.start("3").await;
+        logind.end("1").await;
+        assert!(eventually(async || !registered(&*manager.lock().await)).await);
+        // the user is still logged in to another session, which the lifetimes move on to
+        assert_eq!(manager.lock().await.cache.session.as_deref(), Some("3"));
+    }
+}
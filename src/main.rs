@@ -1,43 +1,192 @@
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_std::sync::{Arc, Mutex};
 
 use async_std::task;
 use entry_management::EntryManager;
+use zbus::fdo::DBusProxy;
 use zbus::{Connection, Result as ZbusResult};
 
+use crate::config::Config;
+use crate::containers::ContainerEngine;
 use crate::daemon::Daemon;
-use crate::entry_management::Lifetime;
-use crate::tools::get_dirs;
+use crate::entry_management::{update_mime_databases, Lifetime};
+use crate::tools::{get_autostart_dir, get_data_home, get_dirs, unix_now};
 
+mod app_index;
+mod config;
+mod containers;
 mod daemon;
+mod desktop_file;
 mod entry_management;
+mod environment;
+mod logind;
+mod path_watch;
+#[cfg(feature = "sqlite")]
+mod sqlite_storage;
+mod storage;
+mod systemd;
+#[cfg(test)]
+mod test_support;
 mod tools;
+mod validation;
 
 #[async_std::main]
 async fn main() -> ZbusResult<()> {
     env_logger::init();
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("rebuild") => return rebuild().await,
+        Some(other) => {
+            eprintln!(
+                "unknown command '{}', usage: desktop-entry-daemon [rebuild]",
+                other
+            );
+            std::process::exit(2);
+        }
+    }
     let (proc_dir, session_dir, persistent_dir, config_file) = get_dirs();
+    let config = Config::load(&config_file.with_file_name("config.ron"));
+    let symlink_fallback = config.symlink_fallback;
+    let roots = vec![
+        persistent_dir.clone(),
+        session_dir.clone(),
+        proc_dir.clone(),
+    ];
+    // autostart entries that end with the session are looked up in these
+    let config_roots = vec![session_dir.join("config"), proc_dir.join("config")];
     let manager = Arc::new(Mutex::new(EntryManager::new(
         proc_dir,
         session_dir,
         persistent_dir,
+        get_autostart_dir(),
+        get_data_home(),
         config_file,
+        config,
     )));
+    if let Err(e) = app_index::watch_app_index(manager.clone()) {
+        log::error!("Something went wrong when watching for app changes {:?}", e);
+    }
+    // start daemon
+    let connection = Connection::session().await?;
+    // session lifetimes last as long as the login session, which may outlive the daemon
+    let system_connection = match Connection::system().await {
+        Ok(system_connection) => Some(system_connection),
+        Err(e) => {
+            log::warn!("could not connect to the system bus: {:?}", e);
+            None
+        }
+    };
+    let session = match &system_connection {
+        Some(system_connection) => match logind::current_session(system_connection).await {
+            Ok(session) => session,
+            Err(e) => {
+                log::warn!("could not get the login session from logind: {:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    if let Err(e) = manager.lock().await.bind_session(session) {
+        log::warn!(
+            "there was a problem resetting the session lifetime: {:?}",
+            e
+        );
+    }
+    // checked before exporting, which would make the session's environment look complete
+    // even though what's already running in it still lacks the directories
+    let missing_data_dirs = environment::missing_data_dirs(&connection, &roots).await;
+    if let Err(e) = environment::export_data_dirs(&connection, &roots).await {
+        log::warn!("could not export XDG_DATA_DIRS to the session: {:?}", e);
+    }
+    if let Err(e) = environment::export_config_dirs(&connection, &config_roots).await {
+        log::warn!("could not export XDG_CONFIG_DIRS to the session: {:?}", e);
+    }
+    if !missing_data_dirs.is_empty() {
+        log::warn!(
+            "XDG_DATA_DIRS is missing {:?}, registered resources in them won't be visible!",
+            missing_data_dirs
+        );
+        if symlink_fallback {
+            log::info!("Exporting entries and icons as symlinks instead...");
+            if let Err(e) = manager.lock().await.export_symlinks() {
+                log::error!("Something went wrong when exporting symlinks {:?}", e);
+            }
+        }
+    }
     let c = manager.clone();
-    let _ = async_std::task::spawn(async { watch_processes(c).await });
+    let conn = connection.clone();
+    async_std::task::spawn(async { watch_processes(c, conn).await });
     let c = manager.clone();
-    provide_desktop_entry_api(c).await?;
+    async_std::task::spawn(async { watch_deadlines(c).await });
+    let c = manager.clone();
+    let conn = connection.clone();
+    async_std::task::spawn(async { watch_leases(c, conn).await });
+    let c = manager.clone();
+    async_std::task::spawn(async {
+        if let Err(e) = path_watch::watch_paths(c).await {
+            log::error!(
+                "Something went wrong when watching for removed paths {:?}",
+                e
+            );
+        }
+    });
+    let c = manager.clone();
+    let conn = connection.clone();
+    async_std::task::spawn(async {
+        if let Err(e) = systemd::watch_units(c, conn).await {
+            log::error!("Something went wrong when watching if units stop {:?}", e);
+        }
+    });
+    for engine in [ContainerEngine::Podman, ContainerEngine::Docker] {
+        let c = manager.clone();
+        async_std::task::spawn(containers::watch_containers(c, engine, engine.socket()));
+    }
+    if let Some(system_connection) = system_connection {
+        let c = manager.clone();
+        async_std::task::spawn(async {
+            if let Err(e) = logind::watch_sessions(c, system_connection).await {
+                log::error!("Something went wrong when watching login sessions {:?}", e);
+            }
+        });
+    }
+    let c = manager.clone();
+    let missing_data_dirs = missing_data_dirs
+        .iter()
+        .map(|x| x.to_string_lossy().to_string())
+        .collect();
+    provide_desktop_entry_api(c, connection, missing_data_dirs).await?;
+    Ok(())
+}
+
+/// asks the running daemon to rebuild its cache from the lifetime directories. the daemon
+/// rebuilds it on its own at startup if it can't be read, so there is nothing to do offline
+async fn rebuild() -> ZbusResult<()> {
+    let connection = Connection::session().await?;
+    let reply = connection
+        .call_method(
+            Some("io.ryanabx.DesktopEntry"),
+            "/io/ryanabx/DesktopEntry",
+            Some("io.ryanabx.DesktopEntry"),
+            "Rebuild",
+            &(),
+        )
+        .await?;
+    let count: u32 = reply.body().deserialize()?;
+    println!("Rebuilt the cache with {} resources", count);
     Ok(())
 }
 
-async fn provide_desktop_entry_api(manager: Arc<Mutex<EntryManager>>) -> zbus::Result<()> {
+async fn provide_desktop_entry_api(
+    manager: Arc<Mutex<EntryManager>>,
+    connection: Connection,
+    missing_data_dirs: Vec<String>,
+) -> zbus::Result<()> {
     let daemon = Daemon {
         entry_manager: manager,
+        missing_data_dirs: missing_data_dirs.clone(),
     };
-    // start daemon
-    let connection = Connection::session().await?;
     // setup the server
     connection
         .object_server()
@@ -45,6 +194,13 @@ async fn provide_desktop_entry_api(manager: Arc<Mutex<EntryManager>>) -> zbus::R
         .await?;
     // before requesting the name
     connection.request_name("io.ryanabx.DesktopEntry").await?;
+    if !missing_data_dirs.is_empty() {
+        let iface = connection
+            .object_server()
+            .interface::<_, Daemon>("/io/ryanabx/DesktopEntry")
+            .await?;
+        Daemon::data_dirs_missing(iface.signal_context(), missing_data_dirs).await?;
+    }
     log::info!("Running server connection and listening for calls");
 
     loop {
@@ -54,18 +210,78 @@ async fn provide_desktop_entry_api(manager: Arc<Mutex<EntryManager>>) -> zbus::R
     }
 }
 
-async fn watch_processes(manager: Arc<Mutex<EntryManager>>) -> zbus::Result<()> {
+/// removes resources whose deadline has passed. deadlines are wall-clock times, so ones that
+/// passed while the daemon wasn't running are removed on the first check
+async fn watch_deadlines(manager: Arc<Mutex<EntryManager>>) {
+    log::info!("Watching for expired deadlines!");
+    loop {
+        let now = unix_now();
+        let mut manager_lock = manager.lock_arc().await;
+        let expired = manager_lock
+            .live_leaves()
+            .into_iter()
+            .filter(|x| x.expired(now))
+            .collect::<Vec<_>>();
+        for x in expired {
+            log::info!("{:?} has expired! Removing associated entries...", x);
+            if manager_lock.end_lifetime(x.clone()).is_err() {
+                log::error!("Something went wrong when removing lifetime {:?}", x);
+            }
+            if manager_lock.save_cache().is_err() {
+                log::error!("Something went wrong when saving the cache to disk {:?}", x);
+            }
+        }
+        drop(manager_lock);
+        task::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// how often the daemon records that it's running, which is how much time leases may gain if
+/// it stops. leases are extended by the time it wasn't running when it starts again
+const LEASE_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// removes the resources of leases that weren't renewed in time
+async fn watch_leases(manager: Arc<Mutex<EntryManager>>, connection: Connection) {
+    log::info!("Watching for lapsed leases!");
+    let mut checkpoint = Instant::now();
+    loop {
+        task::sleep(Duration::from_secs(1)).await;
+        let now = unix_now();
+        let mut manager_lock = manager.lock().await;
+        let lapsed = match manager_lock.lapse_leases(now) {
+            Ok(lapsed) => lapsed,
+            Err(e) => {
+                log::error!("Something went wrong when removing lapsed leases {:?}", e);
+                true
+            }
+        };
+        if checkpoint.elapsed() >= LEASE_CHECKPOINT_INTERVAL {
+            checkpoint = Instant::now();
+            if let Err(e) = manager_lock.checkpoint_leases(now) {
+                log::error!("Something went wrong when saving the cache to disk {:?}", e);
+            }
+        }
+        drop(manager_lock);
+        if lapsed {
+            if let Err(e) = daemon::notify_lease_expirations(&connection).await {
+                log::debug!("could not announce the lapsed leases: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn watch_processes(
+    manager: Arc<Mutex<EntryManager>>,
+    connection: Connection,
+) -> zbus::Result<()> {
     log::info!("Watching if processes exit!");
     loop {
         task::sleep(Duration::from_secs(1)).await;
         // Check if processes have been destroyed
         let mut manager_lock = manager.lock_arc().await;
         let keys_to_iter = manager_lock
-            .cache
-            .entries
-            .keys()
-            .cloned()
-            .chain(manager_lock.cache.icons.keys().cloned())
+            .live_leaves()
+            .into_iter()
             .filter_map(|x| {
                 if let Lifetime::Process(pid) = x {
                     Some(pid)
@@ -77,7 +293,7 @@ async fn watch_processes(manager: Arc<Mutex<EntryManager>>) -> zbus::Result<()>
         for x in keys_to_iter {
             if !Path::new(&format!("/proc/{}", x.clone())).exists() {
                 log::info!("Process {} has exited! Removing associated entries...", x);
-                if manager_lock.remove_lifetime(Lifetime::Process(x)).is_err() {
+                if manager_lock.end_lifetime(Lifetime::Process(x)).is_err() {
                     log::error!("Something went wrong when removing lifetime with PID {}", x);
                 }
                 if manager_lock.save_cache().is_err() {
@@ -99,5 +315,22 @@ async fn watch_processes(manager: Arc<Mutex<EntryManager>>) -> zbus::Result<()>
                 manager_lock.change_handlers.remove(&x);
             }
         }
+        if manager_lock.bus_reload_pending {
+            manager_lock.bus_reload_pending = false;
+            log::info!("D-Bus services changed! Asking the bus to reload its configuration...");
+            let reload = match DBusProxy::new(&connection).await {
+                Ok(dbus_proxy) => dbus_proxy.reload_config().await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = reload {
+                log::error!(
+                    "Something went wrong when reloading the bus configuration {:?}",
+                    e
+                );
+            }
+        }
+        // for mime packages removed along with lifetimes that ended on their own
+        drop(manager_lock);
+        update_mime_databases(&manager).await;
     }
 }
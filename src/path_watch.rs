@@ -0,0 +1,295 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use async_std::channel::{self, Receiver};
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::entry_management::{EntryManager, Lifetime};
+
+/// how long to wait for inotify events before looking for path lifetimes registered since, to
+/// watch their directories too
+const NEW_PATHS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// an inotify event, read on the thread blocking on inotify
+struct Event {
+    wd: WatchDescriptor,
+    mask: EventMask,
+    cookie: u32,
+    name: Option<OsString>,
+}
+
+/// reads inotify events on their own thread, since inotify reads block, and passes them on
+/// to be handled on a task. the thread ends once the receiver is dropped and another event
+/// comes in
+fn read_events(mut inotify: Inotify) -> Receiver<std::io::Result<Vec<Event>>> {
+    let (sender, receiver) = channel::unbounded();
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = inotify.read_events_blocking(&mut buffer).map(|events| {
+                events
+                    .map(|x| Event {
+                        wd: x.wd,
+                        mask: x.mask,
+                        cookie: x.cookie,
+                        name: x.name.map(|x| x.to_owned()),
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let failed = events.is_err();
+            if task::block_on(sender.send(events)).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// removes the resources of path lifetimes once their file is gone, watching the directories
+/// the files are in with inotify. a file renamed within its directory takes its resources
+/// along, unless its lifetime was registered with `remove_on_move`
+pub async fn watch_paths(manager: Arc<Mutex<EntryManager>>) -> std::io::Result<()> {
+    let inotify = Inotify::init()?;
+    let mut watches = inotify.watches();
+    let events = read_events(inotify);
+    let mask = WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVE_SELF;
+    let mut watched = HashMap::<PathBuf, WatchDescriptor>::new();
+    log::info!("Watching if paths are removed!");
+    loop {
+        let dirs = path_lifetimes(&*manager.lock().await)
+            .into_iter()
+            .filter_map(|(path, _)| path.parent().map(|x| x.to_owned()))
+            .collect::<HashSet<_>>();
+        // directories that may have lost files. newly watched ones are checked too, which
+        // catches files removed while the daemon wasn't running
+        let mut changed = HashSet::new();
+        watched.retain(|dir, wd| {
+            if !dirs.contains(dir) {
+                let _ = watches.remove(wd.clone());
+            }
+            dirs.contains(dir)
+        });
+        for dir in dirs {
+            if watched.contains_key(&dir) {
+                continue;
+            }
+            match watches.add(&dir, mask) {
+                Ok(wd) => {
+                    watched.insert(dir.clone(), wd);
+                }
+                Err(e) => log::debug!("could not watch {:?} for removed paths: {:?}", dir, e),
+            }
+            changed.insert(dir);
+        }
+        // waits for events, then takes the rest of those already read
+        let mut batches = Vec::new();
+        match async_std::future::timeout(NEW_PATHS_INTERVAL, events.recv()).await {
+            Ok(Ok(batch)) => batches.push(batch?),
+            Ok(Err(e)) => return Err(std::io::Error::other(e)),
+            Err(_) => {}
+        }
+        while let Ok(batch) = events.try_recv() {
+            batches.push(batch?);
+        }
+        let mut moved_from = HashMap::<u32, PathBuf>::new();
+        let mut moves = HashMap::<PathBuf, PathBuf>::new();
+        for event in batches.into_iter().flatten() {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                log::warn!("missed path changes, checking every path again");
+                changed.extend(watched.keys().cloned());
+                continue;
+            }
+            let Some(dir) = watched
+                .iter()
+                .find(|(_, wd)| **wd == event.wd)
+                .map(|(dir, _)| dir.clone())
+            else {
+                continue;
+            };
+            changed.insert(dir.clone());
+            if event.mask.contains(EventMask::IGNORED) {
+                // the directory itself is gone, so it's watched again if it comes back
+                watched.remove(&dir);
+                continue;
+            }
+            let Some(name) = event.name else {
+                continue;
+            };
+            let path = dir.join(name);
+            if event.mask.contains(EventMask::MOVED_FROM) {
+                moved_from.insert(event.cookie, path);
+            } else if event.mask.contains(EventMask::MOVED_TO) {
+                if let Some(from) = moved_from.remove(&event.cookie) {
+                    moves.insert(from, path);
+                }
+            }
+        }
+        if changed.is_empty() {
+            continue;
+        }
+        let mut manager_lock = manager.lock().await;
+        for (path, remove_on_move) in path_lifetimes(&manager_lock) {
+            if !path.parent().is_some_and(|x| changed.contains(x)) || exists(&path) {
+                continue;
+            }
+            let lifetime = Lifetime::Path {
+                path: path.clone(),
+                remove_on_move,
+            };
+            match moves.get(&path) {
+                Some(to) if !remove_on_move && exists(to) => {
+                    log::info!("{:?} was moved to {:?}! Following it...", path, to);
+                    let new = Lifetime::Path {
+                        path: to.clone(),
+                        remove_on_move,
+                    };
+                    if manager_lock.relabel_leaf(&lifetime, &new).is_err() {
+                        log::error!("Something went wrong when moving lifetime {:?}", path);
+                    }
+                }
+                _ => {
+                    log::info!("{:?} was removed! Removing associated entries...", path);
+                    if manager_lock.end_lifetime(lifetime).is_err() {
+                        log::error!("Something went wrong when removing lifetime {:?}", path);
+                    }
+                }
+            }
+        }
+        if manager_lock.save_cache().is_err() {
+            log::error!("Something went wrong when saving the cache to disk");
+        }
+    }
+}
+
+/// the path and `remove_on_move` of every path lifetime that hasn't ended
+fn path_lifetimes(manager: &EntryManager) -> Vec<(PathBuf, bool)> {
+    manager
+        .live_leaves()
+        .into_iter()
+        .filter_map(|x| {
+            if let Lifetime::Path {
+                path,
+                remove_on_move,
+            } = x
+            {
+                Some((path, remove_on_move))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// whether there is a file at `path`, without following it if it's a symlink
+fn exists(path: &std::path::Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_support::{entry_manager, eventually, TempDir, ENTRY},
+    };
+
+    /// a manager with its path lifetimes watched
+    fn watched_manager(dir: &std::path::Path) -> Arc<Mutex<EntryManager>> {
+        let manager = Arc::new(Mutex::new(entry_manager(dir, Config::default())));
+        task::spawn(watch_paths(manager.clone()));
+        manager
+    }
+
+    /// registers an entry as `appid` that lasts while a file exists at `path`, creating it
+    async fn register(
+        manager: &Mutex<EntryManager>,
+        appid: &str,
+        path: PathBuf,
+        remove_on_move: bool,
+    ) -> Lifetime {
+        fs::write(&path, "").unwrap();
+        let lifetime = Lifetime::from_path(path, remove_on_move).unwrap();
+        manager
+            .lock()
+            .await
+            .register_entry(ENTRY, appid, lifetime.clone())
+            .unwrap();
+        lifetime
+    }
+
+    #[async_std::test]
+    async fn removing_the_path_ends_its_lifetime() {
+        let dir = TempDir::new();
+        let manager = watched_manager(dir.path());
+        let apps = dir.path().join("apps");
+        fs::create_dir(&apps).unwrap();
+        let registered = async |appid: &str| manager.lock().await.entry_for_appid(appid);
+
+        // paths registered while watching are picked up too
+        register(
+            &manager,
+            "com.example.Foo",
+            apps.join("Foo.AppImage"),
+            false,
+        )
+        .await;
+        assert!(registered("com.example.Foo").await.is_some());
+        fs::remove_file(apps.join("Foo.AppImage")).unwrap();
+        assert!(eventually(async || registered("com.example.Foo").await.is_none()).await);
+        assert!(manager.lock().await.live_leaves().is_empty());
+    }
+
+    #[async_std::test]
+    async fn moved_paths_take_their_lifetime_along_unless_asked_not_to() {
+        let dir = TempDir::new();
+        let manager = watched_manager(dir.path());
+        let apps = dir.path().join("apps");
+        fs::create_dir(&apps).unwrap();
+        let registered = async |appid: &str| manager.lock().await.entry_for_appid(appid);
+        register(
+            &manager,
+            "com.example.Foo",
+            apps.join("Foo.AppImage"),
+            false,
+        )
+        .await;
+        register(&manager, "com.example.Bar", apps.join("Bar.AppImage"), true).await;
+
+        // once a removal in the directory is noticed, it's known to be watched
+        register(
+            &manager,
+            "com.example.Baz",
+            apps.join("Baz.AppImage"),
+            false,
+        )
+        .await;
+        fs::remove_file(apps.join("Baz.AppImage")).unwrap();
+        assert!(eventually(async || registered("com.example.Baz").await.is_none()).await);
+
+        fs::rename(apps.join("Foo.AppImage"), apps.join("Foo-2.AppImage")).unwrap();
+        fs::rename(apps.join("Bar.AppImage"), apps.join("Bar-2.AppImage")).unwrap();
+        assert!(eventually(async || registered("com.example.Bar").await.is_none()).await);
+        let moved = Lifetime::from_path(apps.join("Foo-2.AppImage"), false).unwrap();
+        assert!(
+            eventually(async || {
+                registered("com.example.Foo")
+                    .await
+                    .is_some_and(|(lifetime, _)| lifetime == moved)
+            })
+            .await
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::{
+    entry_management::{
+        add_claim, cache_backup_path, ConfigError, EntryCache, EntryManagerError, Lifetime,
+    },
+    storage::{Change, Record, Storage},
+};
+
+/// the version of `SCHEMA`, recorded in the `meta` table. it's separate from the version of
+/// the ron cache, which changes for reasons that don't concern the database. bump it when
+/// `SCHEMA` changes in a way existing databases can't be read with, and migrate them
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS records (
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL,
+    lifetime TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (kind, key, lifetime)
+);
+CREATE INDEX IF NOT EXISTS records_lifetime ON records (lifetime);
+CREATE TABLE IF NOT EXISTS namespace_modes (
+    owner TEXT PRIMARY KEY,
+    mode TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS leases (
+    id TEXT PRIMARY KEY,
+    data TEXT NOT NULL
+);
+";
+
+/// stores the cache in an sqlite database, one row per registered resource and lifetime
+/// claiming it, so a change only writes the rows it touches. rows are indexed by lifetime, so
+/// a lifetime ending removes its rows without a scan. the claims on a resource are loaded in
+/// the order their rows were inserted, which keeps the claim the file is kept for first
+#[derive(Debug)]
+pub struct SqliteStorage {
+    path: PathBuf,
+    /// the ron cache used before this backend was chosen, imported on first load
+    ron_path: PathBuf,
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    /// opens the database at `path`, creating it if needed
+    pub fn open(path: PathBuf, ron_path: PathBuf) -> rusqlite::Result<Self> {
+        let connection = Connection::open(&path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            path,
+            ron_path,
+            connection,
+        })
+    }
+
+    /// imports the ron cache, then moves it out of the way so it's only imported once
+    fn import_ron(&mut self) -> Result<EntryCache, ConfigError> {
+        let cache = EntryCache::new(&self.ron_path)?;
+        self.commit(&cache, &[Change::All])
+            .map_err(|e| ConfigError::Import(format!("{:?}", e)))?;
+        let backup = cache_backup_path(&self.ron_path, "imported");
+        fs::rename(&self.ron_path, &backup)?;
+        log::info!(
+            "Imported {:?} into the sqlite cache, moved it to {:?}",
+            self.ron_path,
+            backup
+        );
+        Ok(cache)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&mut self) -> Result<EntryCache, ConfigError> {
+        let version = self
+            .connection
+            .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?;
+        let Some(version) = version else {
+            // a new database, which starts from the ron cache if there is one
+            return self.import_ron();
+        };
+        let version = version.parse::<u32>().unwrap_or(u32::MAX);
+        if version != SCHEMA_VERSION {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+        let mut cache = EntryCache::default();
+        let mut statement = self
+            .connection
+            .prepare("SELECT kind, lifetime, data FROM records ORDER BY rowid")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let kind: String = row.get(0)?;
+            let lifetime: Lifetime = ron::from_str(&row.get::<_, String>(1)?)?;
+            let data: String = row.get(2)?;
+            match kind.as_str() {
+                "entry" => add_claim(&mut cache.entries, lifetime, ron::from_str(&data)?),
+                "icon" => add_claim(&mut cache.icons, lifetime, ron::from_str(&data)?),
+                "resource" => add_claim(&mut cache.resources, lifetime, ron::from_str(&data)?),
+                "association" => {
+                    add_claim(&mut cache.associations, lifetime, ron::from_str(&data)?)
+                }
+                _ => log::warn!("skipping cache record of unknown kind {}", kind),
+            }
+        }
+        let mut statement = self
+            .connection
+            .prepare("SELECT owner, mode FROM namespace_modes")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            cache
+                .namespace_modes
+                .insert(row.get(0)?, ron::from_str(&row.get::<_, String>(1)?)?);
+        }
+        let mut statement = self.connection.prepare("SELECT id, data FROM leases")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            cache
+                .leases
+                .insert(row.get(0)?, ron::from_str(&row.get::<_, String>(1)?)?);
+        }
+        cache.session = self
+            .connection
+            .query_row("SELECT value FROM meta WHERE key = 'session'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        cache.alive_at = self
+            .connection
+            .query_row("SELECT value FROM meta WHERE key = 'alive_at'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?
+            .and_then(|x| x.parse().ok())
+            .unwrap_or_default();
+        Ok(cache)
+    }
+
+    fn commit(&mut self, cache: &EntryCache, changes: &[Change]) -> Result<(), EntryManagerError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let transaction = self.connection.transaction()?;
+        for change in changes {
+            match change {
+                Change::Insert(lifetime, record) => insert(&transaction, lifetime, record)?,
+                Change::Remove(lifetime, record) => {
+                    let (kind, key, _) = columns(record)?;
+                    transaction.execute(
+                        "DELETE FROM records WHERE kind = ?1 AND key = ?2 AND lifetime = ?3",
+                        params![kind, key, ron::to_string(lifetime)?],
+                    )?;
+                }
+                Change::RemoveLifetime(lifetime) => {
+                    transaction.execute(
+                        "DELETE FROM records WHERE lifetime = ?1",
+                        params![ron::to_string(lifetime)?],
+                    )?;
+                }
+                Change::NamespaceMode(owner, Some(mode)) => {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO namespace_modes (owner, mode) VALUES (?1, ?2)",
+                        params![owner, ron::to_string(mode)?],
+                    )?;
+                }
+                Change::NamespaceMode(owner, None) => {
+                    transaction.execute(
+                        "DELETE FROM namespace_modes WHERE owner = ?1",
+                        params![owner],
+                    )?;
+                }
+                Change::Session(session) => set_session(&transaction, session.as_deref())?,
+                Change::Lease(id, Some(lease)) => {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO leases (id, data) VALUES (?1, ?2)",
+                        params![id, ron::to_string(lease)?],
+                    )?;
+                }
+                Change::Lease(id, None) => {
+                    transaction.execute("DELETE FROM leases WHERE id = ?1", params![id])?;
+                }
+                Change::AliveAt(time) => set_alive_at(&transaction, *time)?,
+                Change::All => replace(&transaction, cache)?,
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// replaces every row with the contents of `cache`
+fn replace(transaction: &Transaction, cache: &EntryCache) -> Result<(), EntryManagerError> {
+    transaction.execute("DELETE FROM records", [])?;
+    transaction.execute("DELETE FROM namespace_modes", [])?;
+    transaction.execute("DELETE FROM leases", [])?;
+    let records = cache
+        .entries
+        .iter()
+        .flat_map(|x| {
+            x.claims
+                .iter()
+                .map(|l| (l, Record::Entry(x.handle.clone())))
+        })
+        .chain(
+            cache
+                .icons
+                .iter()
+                .flat_map(|x| x.claims.iter().map(|l| (l, Record::Icon(x.handle.clone())))),
+        )
+        .chain(cache.resources.iter().flat_map(|x| {
+            x.claims
+                .iter()
+                .map(|l| (l, Record::Resource(x.handle.clone())))
+        }))
+        .chain(cache.associations.iter().flat_map(|x| {
+            x.claims
+                .iter()
+                .map(|l| (l, Record::Association(x.handle.clone())))
+        }));
+    for (lifetime, record) in records {
+        insert(transaction, lifetime, &record)?;
+    }
+    for (owner, mode) in &cache.namespace_modes {
+        transaction.execute(
+            "INSERT INTO namespace_modes (owner, mode) VALUES (?1, ?2)",
+            params![owner, ron::to_string(mode)?],
+        )?;
+    }
+    for (id, lease) in &cache.leases {
+        transaction.execute(
+            "INSERT INTO leases (id, data) VALUES (?1, ?2)",
+            params![id, ron::to_string(lease)?],
+        )?;
+    }
+    set_session(transaction, cache.session.as_deref())?;
+    set_alive_at(transaction, cache.alive_at)?;
+    transaction.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('version', ?1)",
+        params![SCHEMA_VERSION.to_string()],
+    )?;
+    Ok(())
+}
+
+/// records the logind session that session lifetimes belong to
+fn set_session(transaction: &Transaction, session: Option<&str>) -> Result<(), EntryManagerError> {
+    match session {
+        Some(session) => transaction.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('session', ?1)",
+            params![session],
+        )?,
+        None => transaction.execute("DELETE FROM meta WHERE key = 'session'", [])?,
+    };
+    Ok(())
+}
+
+/// records the last time the daemon was known to be running
+fn set_alive_at(transaction: &Transaction, time: u64) -> Result<(), EntryManagerError> {
+    transaction.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('alive_at', ?1)",
+        params![time.to_string()],
+    )?;
+    Ok(())
+}
+
+/// stores the claim of `lifetime` on `record`, updating the row for the same file or mime
+/// type and lifetime in place, so it keeps its place among the claims
+fn insert(
+    transaction: &Transaction,
+    lifetime: &Lifetime,
+    record: &Record,
+) -> Result<(), EntryManagerError> {
+    let (kind, key, data) = columns(record)?;
+    transaction.execute(
+        "INSERT INTO records (kind, key, lifetime, data) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (kind, key, lifetime) DO UPDATE SET data = excluded.data",
+        params![kind, key, ron::to_string(lifetime)?, data],
+    )?;
+    Ok(())
+}
+
+/// the kind, key, and serialized data of `record`. the key is the path of the file, or the
+/// mime type of an association
+fn columns(record: &Record) -> Result<(&'static str, String, String), EntryManagerError> {
+    Ok(match record {
+        Record::Entry(x) => (
+            "entry",
+            x.path.to_string_lossy().to_string(),
+            ron::to_string(x)?,
+        ),
+        Record::Icon(x) => (
+            "icon",
+            x.icon_path.to_string_lossy().to_string(),
+            ron::to_string(x)?,
+        ),
+        Record::Resource(x) => (
+            "resource",
+            x.path.to_string_lossy().to_string(),
+            ron::to_string(x)?,
+        ),
+        Record::Association(x) => ("association", x.mime_type.clone(), ron::to_string(x)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        entry_management::{
+            Claimed, DesktopHandle, IconHandle, Lease, MimeAssociation, NamespaceMode,
+            ResourceHandle, ResourceType,
+        },
+        storage::RonStorage,
+        test_support::TempDir,
+    };
+
+    fn open(dir: &Path) -> SqliteStorage {
+        SqliteStorage::open(dir.join("cache.sqlite"), dir.join("cache.ron")).unwrap()
+    }
+
+    fn entry(appid: &str) -> DesktopHandle {
+        DesktopHandle {
+            appid: appid.to_string(),
+            path: PathBuf::from(format!("/apps/{}.desktop", appid)),
+            link: None,
+        }
+    }
+
+    /// a cache with one of everything, and claims listed against their sort order
+    fn cache() -> EntryCache {
+        let session = Lifetime::Session("a".to_string());
+        let persistent = Lifetime::Persistent("b".to_string());
+        EntryCache {
+            entries: vec![
+                Claimed {
+                    handle: entry("com.example.Foo"),
+                    claims: vec![persistent.clone(), session.clone()],
+                },
+                Claimed::new(entry("com.example.Bar"), Lifetime::Process(1)),
+            ],
+            icons: vec![Claimed::new(
+                IconHandle {
+                    icon_name: "foo".to_string(),
+                    icon_path: PathBuf::from("/icons/foo.png"),
+                    link: None,
+                },
+                session.clone(),
+            )],
+            resources: vec![Claimed {
+                handle: ResourceHandle {
+                    resource_type: ResourceType::DBusService,
+                    name: "com.example.Foo".to_string(),
+                    path: PathBuf::from("/services/com.example.Foo.service"),
+                },
+                claims: vec![persistent.clone(), session],
+            }],
+            associations: vec![Claimed::new(
+                MimeAssociation {
+                    mime_type: "text/plain".to_string(),
+                    desktop_file: "com.example.Foo.desktop".to_string(),
+                },
+                persistent,
+            )],
+            namespace_modes: [("a".to_string(), NamespaceMode::Prefix)].into(),
+            session: Some("1".to_string()),
+            leases: [(
+                "id".to_string(),
+                Lease {
+                    interval: 60,
+                    grace: 10,
+                    expires: 1000,
+                },
+            )]
+            .into(),
+            alive_at: 900,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn caches_are_loaded_as_they_were_stored() {
+        let dir = TempDir::new();
+        let cache = cache();
+        let mut storage = open(dir.path());
+        storage.commit(&cache, &[Change::All]).unwrap();
+        drop(storage);
+        assert_eq!(open(dir.path()).load().unwrap(), cache);
+    }
+
+    #[test]
+    fn changes_only_touch_their_own_rows() {
+        let dir = TempDir::new();
+        let mut cache = cache();
+        let mut storage = open(dir.path());
+        storage.commit(&cache, &[Change::All]).unwrap();
+
+        // updating a claim keeps its place, so the claim the file is kept for stays first
+        let foo = Record::Entry(entry("com.example.Foo"));
+        let persistent = Lifetime::Persistent("b".to_string());
+        let session = Lifetime::Session("a".to_string());
+        storage
+            .commit(&cache, &[Change::Insert(persistent.clone(), foo.clone())])
+            .unwrap();
+        assert_eq!(storage.load().unwrap(), cache);
+
+        let bar = Record::Entry(entry("com.example.Bar"));
+        storage
+            .commit(&cache, &[Change::Remove(Lifetime::Process(1), bar)])
+            .unwrap();
+        cache.entries.remove(1);
+        assert_eq!(storage.load().unwrap(), cache);
+
+        storage
+            .commit(&cache, &[Change::RemoveLifetime(persistent)])
+            .unwrap();
+        let loaded = storage.load().unwrap();
+        assert_eq!(
+            loaded.entries,
+            vec![Claimed::new(entry("com.example.Foo"), session.clone())]
+        );
+        assert_eq!(loaded.resources[0].claims, vec![session.clone()]);
+        assert!(loaded.associations.is_empty());
+        assert_eq!(loaded.icons, cache.icons);
+    }
+
+    #[test]
+    fn the_ron_cache_is_imported_once() {
+        let dir = TempDir::new();
+        let cache = cache();
+        RonStorage::new(dir.path().join("cache.ron"))
+            .commit(&cache, &[])
+            .unwrap();
+        assert_eq!(open(dir.path()).load().unwrap(), cache);
+        assert!(!dir.path().join("cache.ron").exists());
+        assert!(dir.path().join("cache.ron.imported.bak").exists());
+
+        // and the database is what's loaded from then on
+        let mut storage = open(dir.path());
+        storage
+            .commit(&cache, &[Change::RemoveLifetime(Lifetime::Process(1))])
+            .unwrap();
+        assert_eq!(storage.load().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn databases_from_other_schema_versions_are_refused() {
+        let dir = TempDir::new();
+        let mut storage = open(dir.path());
+        storage.commit(&cache(), &[Change::All]).unwrap();
+        storage
+            .connection
+            .execute(
+                "UPDATE meta SET value = ?1 WHERE key = 'version'",
+                params![(SCHEMA_VERSION + 1).to_string()],
+            )
+            .unwrap();
+        assert!(matches!(
+            storage.load(),
+            Err(ConfigError::UnsupportedVersion(x)) if x == SCHEMA_VERSION + 1
+        ));
+    }
+}
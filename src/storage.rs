@@ -0,0 +1,121 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::entry_management::{
+    ConfigError, DesktopHandle, EntryCache, EntryManagerError, IconHandle, Lease, Lifetime,
+    MimeAssociation, NamespaceMode, ResourceHandle,
+};
+
+/// which backend the daemon stores its record of registered resources in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageBackend {
+    /// a ron file, rewritten whenever anything changes
+    #[default]
+    Ron,
+    /// an embedded sqlite database, changed one record at a time. only available when the
+    /// daemon is built with the `sqlite` feature
+    Sqlite,
+}
+
+/// a single registered resource, as stored by a backend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    Entry(DesktopHandle),
+    Icon(IconHandle),
+    Resource(ResourceHandle),
+    Association(MimeAssociation),
+}
+
+/// a change made to the cache that hasn't been stored yet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Insert(Lifetime, Record),
+    Remove(Lifetime, Record),
+    RemoveLifetime(Lifetime),
+    /// the namespace mode of an owner was set, or reset to the default if `None`
+    NamespaceMode(String, Option<NamespaceMode>),
+    /// session lifetimes now belong to this logind session
+    Session(Option<String>),
+    /// the lease with this id was started or renewed, or ended if `None`
+    Lease(String, Option<Lease>),
+    /// the daemon was known to be running at this time
+    AliveAt(u64),
+    /// anything may have changed, i.e. the cache was migrated or rebuilt
+    All,
+}
+
+/// where the daemon keeps its record of registered resources between runs. the daemon reads
+/// from its cache in memory, and tells the backend about every change made to it
+pub trait Storage: Send + std::fmt::Debug {
+    /// the file the backend stores into, which is backed up if it can't be loaded
+    fn path(&self) -> &Path;
+
+    /// loads every registered resource
+    fn load(&mut self) -> Result<EntryCache, ConfigError>;
+
+    /// stores `changes`, which have already been made to `cache`
+    fn commit(&mut self, cache: &EntryCache, changes: &[Change]) -> Result<(), EntryManagerError>;
+}
+
+/// stores the cache as a single ron file
+#[derive(Debug)]
+pub struct RonStorage {
+    path: PathBuf,
+}
+
+impl RonStorage {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Storage for RonStorage {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&mut self) -> Result<EntryCache, ConfigError> {
+        EntryCache::new(&self.path)
+    }
+
+    fn commit(&mut self, cache: &EntryCache, _changes: &[Change]) -> Result<(), EntryManagerError> {
+        let conf_str = ron::ser::to_string_pretty(cache, ron::ser::PrettyConfig::default())?;
+        fs::write(&self.path, conf_str)?;
+        Ok(())
+    }
+}
+
+/// the storage for `backend`, keeping its files next to the ron cache at `ron_path`. falls
+/// back to the ron file if the backend isn't built in
+pub fn open(backend: StorageBackend, ron_path: PathBuf) -> Box<dyn Storage> {
+    match backend {
+        StorageBackend::Ron => Box::new(RonStorage::new(ron_path)),
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => {
+            let path = ron_path.with_file_name("cache.sqlite");
+            match crate::sqlite_storage::SqliteStorage::open(path, ron_path.clone()) {
+                Ok(storage) => Box::new(storage),
+                Err(e) => {
+                    log::error!(
+                        "could not open the sqlite cache, using {:?}: {}",
+                        ron_path,
+                        e
+                    );
+                    Box::new(RonStorage::new(ron_path))
+                }
+            }
+        }
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackend::Sqlite => {
+            log::warn!(
+                "the sqlite storage backend isn't built in, using {:?}",
+                ron_path
+            );
+            Box::new(RonStorage::new(ron_path))
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::{collections::HashSet, time::Duration};
+
+use async_std::stream::StreamExt;
+use async_std::sync::{Arc, Mutex};
+use zbus::{
+    proxy, zvariant::OwnedObjectPath, CacheProperties, Connection, MatchRule, MessageStream,
+};
+
+use crate::entry_management::{EntryManager, Lifetime};
+
+/// the parts of the systemd user manager the daemon talks to
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+pub trait Manager {
+    /// adds or replaces variables in the environment passed to units the manager starts
+    fn set_environment(&self, assignments: &[&str]) -> zbus::Result<()>;
+
+    /// the object path of the loaded unit `name`
+    fn get_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+
+    /// asks the manager to emit signals about units, which it only does while a client is
+    /// subscribed
+    fn subscribe(&self) -> zbus::Result<()>;
+
+    /// the unit `id` was unloaded
+    #[zbus(signal)]
+    fn unit_removed(&self, id: String, unit: OwnedObjectPath) -> zbus::Result<()>;
+
+    /// the environment passed to units the manager starts, as `KEY=value` assignments
+    #[zbus(property)]
+    fn environment(&self) -> zbus::Result<Vec<String>>;
+}
+
+/// a unit loaded by the systemd user manager
+#[proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+pub trait Unit {
+    /// i.e. `active`, `activating`, `deactivating`, `inactive`, or `failed`
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+
+    /// the id and path of the job queued for the unit. the id is 0 if there is none
+    #[zbus(property)]
+    fn job(&self) -> zbus::Result<(u32, OwnedObjectPath)>;
+}
+
+/// the object path systemd serves the unit `name` at. every byte that isn't a letter, or a
+/// digit after the first byte, is escaped as `_` and its hex value
+pub fn unit_path(name: &str) -> String {
+    let mut path = "/org/freedesktop/systemd1/unit/".to_string();
+    if name.is_empty() {
+        path.push('_');
+    }
+    for (i, byte) in name.bytes().enumerate() {
+        if byte.is_ascii_alphabetic() || (i > 0 && byte.is_ascii_digit()) {
+            path.push(byte as char);
+        } else {
+            path.push_str(&format!("_{:02x}", byte));
+        }
+    }
+    path
+}
+
+/// whether the unit `name` has stopped for good: it isn't loaded, or it's inactive or failed
+/// without a job queued to start it again, as when it's being restarted
+pub async fn unit_stopped(connection: &Connection, name: &str) -> zbus::Result<bool> {
+    let manager = ManagerProxy::new(connection).await?;
+    let path = match manager.get_unit(name).await {
+        Ok(path) => path,
+        Err(zbus::Error::MethodError(error, _, _))
+            if error.as_str() == "org.freedesktop.systemd1.NoSuchUnit" =>
+        {
+            return Ok(true)
+        }
+        Err(e) => return Err(e),
+    };
+    let unit = UnitProxy::builder(connection)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    if !matches!(unit.active_state().await?.as_str(), "inactive" | "failed") {
+        return Ok(false);
+    }
+    let (job, _) = unit.job().await?;
+    Ok(job == 0)
+}
+
+/// removes the resources of systemd user units once they stop. the units' `ActiveState` is
+/// followed through the signals the manager emits, and each unit is checked once when it's
+/// first seen, which catches units that stopped while the daemon wasn't running
+pub async fn watch_units(
+    manager: Arc<Mutex<EntryManager>>,
+    connection: Connection,
+) -> zbus::Result<()> {
+    let rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.systemd1")?
+        .build();
+    let mut signals = MessageStream::for_match_rule(rule, &connection, None).await?;
+    if let Err(e) = ManagerProxy::new(&connection).await?.subscribe().await {
+        log::warn!("could not subscribe to signals from systemd: {}", e);
+    }
+    log::info!("Watching if units stop!");
+    let mut watched = HashSet::<String>::new();
+    loop {
+        let units = manager
+            .lock()
+            .await
+            .live_leaves()
+            .into_iter()
+            .filter_map(|x| match x {
+                Lifetime::Unit(name) => Some(name),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        watched.retain(|x| units.contains(x));
+        let mut changed = units
+            .into_iter()
+            .filter(|x| watched.insert(x.clone()))
+            .collect::<Vec<_>>();
+        // new units are picked up within a second, without waiting for a signal
+        match async_std::future::timeout(Duration::from_secs(1), signals.next()).await {
+            Err(_) => {}
+            Ok(None) => return Ok(()),
+            Ok(Some(message)) => {
+                let message = message?;
+                let header = message.header();
+                if let Some(removed) = UnitRemoved::from_message(message.clone()) {
+                    changed.push(removed.args()?.id);
+                } else if header.member().is_some_and(|x| x == "PropertiesChanged") {
+                    if let Some(path) = header.path() {
+                        changed.extend(
+                            watched
+                                .iter()
+                                .filter(|x| unit_path(x) == path.as_str())
+                                .cloned(),
+                        );
+                    }
+                }
+            }
+        }
+        // the manager isn't locked while asking systemd, which may take a while
+        changed.retain(|x| watched.contains(x));
+        changed.sort();
+        changed.dedup();
+        for unit in changed {
+            match unit_stopped(&connection, &unit).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    log::info!("Unit {} has stopped! Removing associated entries...", unit);
+                    watched.remove(&unit);
+                    let mut manager_lock = manager.lock().await;
+                    if manager_lock
+                        .end_lifetime(Lifetime::Unit(unit.clone()))
+                        .is_err()
+                    {
+                        log::error!(
+                            "Something went wrong when removing lifetime of unit {}",
+                            unit
+                        );
+                    }
+                    if manager_lock.save_cache().is_err() {
+                        log::error!(
+                            "Something went wrong when saving the cache to disk {}",
+                            unit
+                        );
+                    }
+                }
+                Err(e) => log::warn!("could not get the state of unit {}: {}", unit, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as SyncMutex;
+
+    use async_std::task;
+    use zbus::{
+        interface,
+        zvariant::{ObjectPath, OwnedObjectPath},
+        SignalContext,
+    };
+
+    use super::*;
+    use crate::{
+        config::Config,
+        test_support::{entry_manager, eventually, PrivateBus, TempDir, ENTRY},
+    };
+
+    #[derive(Debug, zbus::DBusError)]
+    #[zbus(prefix = "org.freedesktop.systemd1")]
+    enum FakeError {
+        #[zbus(error)]
+        ZBus(zbus::Error),
+        NoSuchUnit(String),
+    }
+
+    /// stands in for the systemd user manager, which has the units in `units` loaded
+    struct FakeManager {
+        units: Arc<SyncMutex<HashSet<String>>>,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Manager")]
+    impl FakeManager {
+        fn get_unit(&self, name: String) -> Result<OwnedObjectPath, FakeError> {
+            if !self.units.lock().unwrap().contains(&name) {
+                return Err(FakeError::NoSuchUnit(name));
+            }
+            Ok(ObjectPath::try_from(unit_path(&name)).unwrap().into())
+        }
+
+        fn subscribe(&self) {}
+
+        #[zbus(signal)]
+        async fn unit_removed(
+            ctxt: &SignalContext<'_>,
+            id: &str,
+            unit: ObjectPath<'_>,
+        ) -> zbus::Result<()>;
+    }
+
+    struct FakeUnit {
+        state: String,
+        job: u32,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Unit")]
+    impl FakeUnit {
+        #[zbus(property)]
+        fn active_state(&self) -> String {
+            self.state.clone()
+        }
+
+        #[zbus(property)]
+        fn job(&self) -> (u32, OwnedObjectPath) {
+            (self.job, ObjectPath::try_from("/").unwrap().into())
+        }
+    }
+
+    /// a systemd user manager on a private bus, whose units the test starts and stops
+    struct FakeSystemd {
+        connection: Connection,
+        units: Arc<SyncMutex<HashSet<String>>>,
+    }
+
+    impl FakeSystemd {
+        async fn new(bus: &PrivateBus) -> Self {
+            let connection = bus.connect().await;
+            let units = Arc::new(SyncMutex::new(HashSet::new()));
+            connection
+                .object_server()
+                .at(
+                    "/org/freedesktop/systemd1",
+                    FakeManager {
+                        units: units.clone(),
+                    },
+                )
+                .await
+                .unwrap();
+            connection
+                .request_name("org.freedesktop.systemd1")
+                .await
+                .unwrap();
+            Self { connection, units }
+        }
+
+        async fn load(&self, name: &str, state: &str) {
+            self.units.lock().unwrap().insert(name.to_string());
+            let unit = FakeUnit {
+                state: state.to_string(),
+                job: 0,
+            };
+            self.connection
+                .object_server()
+                .at(unit_path(name), unit)
+                .await
+                .unwrap();
+        }
+
+        async fn set_state(&self, name: &str, state: &str, job: u32) {
+            let iface = self
+                .connection
+                .object_server()
+                .interface::<_, FakeUnit>(unit_path(name))
+                .await
+                .unwrap();
+            let mut unit = iface.get_mut().await;
+            unit.state = state.to_string();
+            unit.job = job;
+            unit.job_changed(iface.signal_context()).await.unwrap();
+            unit.active_state_changed(iface.signal_context())
+                .await
+                .unwrap();
+        }
+
+        async fn unload(&self, name: &str) {
+            self.units.lock().unwrap().remove(name);
+            let path = unit_path(name);
+            self.connection
+                .object_server()
+                .remove::<FakeUnit, _>(path.as_str())
+                .await
+                .unwrap();
+            let ctxt = SignalContext::new(&self.connection, "/org/freedesktop/systemd1").unwrap();
+            FakeManager::unit_removed(&ctxt, name, ObjectPath::try_from(path).unwrap())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn unit_paths_are_escaped_like_systemd() {
+        assert_eq!(
+            unit_path("podman-foo.service"),
+            "/org/freedesktop/systemd1/unit/podman_2dfoo_2eservice"
+        );
+        assert_eq!(
+            unit_path("1a@b.scope"),
+            "/org/freedesktop/systemd1/unit/_31a_40b_2escope"
+        );
+    }
+
+    #[async_std::test]
+    async fn unit_lifetimes_end_when_the_unit_stops() {
+        let bus = PrivateBus::new();
+        let systemd = FakeSystemd::new(&bus).await;
+        systemd.load("foo.service", "active").await;
+        systemd.load("bar@1.service", "active").await;
+        let dir = TempDir::new();
+        let manager = Arc::new(Mutex::new(entry_manager(dir.path(), Config::default())));
+        for (appid, unit) in [
+            ("com.example.Foo", "foo.service"),
+            ("com.example.Bar", "bar@1.service"),
+            ("com.example.Gone", "gone.service"),
+        ] {
+            manager
+                .lock()
+                .await
+                .register_entry(ENTRY, appid, Lifetime::Unit(unit.to_string()))
+                .unwrap();
+        }
+        let registered = |appid: &'static str| {
+            let manager = manager.clone();
+            async move { manager.lock().await.entry_for_appid(appid).is_some() }
+        };
+        task::spawn(watch_units(manager.clone(), bus.connect().await));
+
+        // a unit that isn't loaded when the daemon starts has stopped already
+        assert!(eventually(async || !registered("com.example.Gone").await).await);
+        assert!(registered("com.example.Foo").await);
+
+        // restarting passes through inactive with a job queued to start the unit again
+        systemd.set_state("foo.service", "inactive", 7).await;
+        task::sleep(Duration::from_secs(2)).await;
+        assert!(registered("com.example.Foo").await);
+        systemd.set_state("foo.service", "active", 0).await;
+
+        systemd.set_state("foo.service", "failed", 0).await;
+        assert!(eventually(async || !registered("com.example.Foo").await).await);
+        assert!(registered("com.example.Bar").await);
+
+        systemd.unload("bar@1.service").await;
+        assert!(eventually(async || !registered("com.example.Bar").await).await);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use zbus::Connection;
+
+use crate::{config::Config, entry_management::EntryManager};
+
+/// an entry that passes validation at every threshold
+pub const ENTRY: &str = "[Desktop Entry]\nType=Application\nName=Foo\nExec=foo\n";
+
+/// a directory removed again when the test is done with it
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "desktop-entry-daemon-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// an entry manager keeping its lifetime directories and cache under `dir`
+pub fn entry_manager(dir: &Path, config: Config) -> EntryManager {
+    let root = |name: &str| {
+        let root = dir.join(name);
+        std::fs::create_dir_all(root.join("applications")).unwrap();
+        std::fs::create_dir_all(root.join("icons")).unwrap();
+        root
+    };
+    std::fs::create_dir_all(dir.join("config")).unwrap();
+    EntryManager::new(
+        root("process"),
+        root("session"),
+        root("persistent"),
+        dir.join("autostart"),
+        dir.join("data"),
+        dir.join("config/cache.ron"),
+        config,
+    )
+}
+
+/// a message bus of its own, so tests can stand in for system services without touching the
+/// real ones. the bus exits when this is dropped
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    pub fn new() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed to run this test");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_string(),
+        }
+    }
+
+    /// a new connection to the bus
+    pub async fn connect(&self) -> Connection {
+        zbus::connection::Builder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// waits up to five seconds for `condition` to hold, checking it every 100ms
+pub async fn eventually(mut condition: impl AsyncFnMut() -> bool) -> bool {
+    for _ in 0..50 {
+        if condition().await {
+            return true;
+        }
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
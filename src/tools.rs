@@ -1,39 +1,311 @@
 use std::{
     env, fs,
     path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use freedesktop_desktop_entry::{default_paths, DesktopEntry, Iter};
+use freedesktop_desktop_entry::DesktopEntry;
+use quick_xml::{events::Event, Reader};
 
-use crate::daemon::ValidationError;
+use crate::{
+    daemon::ValidationError,
+    desktop_file::{self, DesktopFile},
+    entry_management::Lifetime,
+    validation::{self, Diagnostic, Threshold},
+};
 
 /// validate a desktop entry. takes in an entry path and returns the resulting desktop
-/// entry string and the application id
-pub fn validate_desktop_entry(entry: &str, appid: &str) -> Result<String, ValidationError> {
+/// entry string, normalized, along with the diagnostics that didn't reach `threshold`. the
+/// entry is checked against the spec, and rejected if any diagnostic reaches `threshold`. whether the application id is already installed is checked
+/// separately against the app index
+pub fn validate_desktop_entry(
+    entry: &str,
+    appid: &str,
+    threshold: Threshold,
+) -> Result<(String, Vec<Diagnostic>), ValidationError> {
     log::debug!("appid: {}", appid);
     log::trace!("entry: {}", entry);
+    if !is_file_name(appid) {
+        return Err(ValidationError::NotValid(format!(
+            "'{}' is not a valid app id",
+            appid
+        )));
+    }
     // TODO: Extra validation (strip exec, etc...)
-    if let Err(e) = DesktopEntry::decode(Path::new(&format!("{}.desktop", appid)), &entry) {
+    if let Err(e) = DesktopEntry::decode(Path::new(&format!("{}.desktop", appid)), entry) {
         log::error!("Warning: Desktop file failed validation");
-        Err(ValidationError::NotValid(e.to_string()))
-    } else if app_exists(appid) {
-        Err(ValidationError::DuplicateAppID)
-    } else {
-        Ok(entry.to_string())
+        return Err(ValidationError::NotValid(e.to_string()));
+    }
+    let diagnostics = validation::validate(entry);
+    if diagnostics.iter().any(|x| threshold.rejects(x)) {
+        log::error!("Warning: Desktop file failed validation");
+        return Err(ValidationError::Diagnostics(diagnostics));
+    }
+    for diagnostic in &diagnostics {
+        log::warn!("{}.desktop {}", appid, diagnostic);
+    }
+    Ok((normalize_desktop_entry(entry), diagnostics))
+}
+
+/// returns `entry` in canonical form: utf-8 without a byte order mark, LF line endings, no
+/// space around `=`, duplicate groups and keys merged, and keys in a stable order. comments,
+/// localized keys, and lines that can't be parsed are kept
+pub fn normalize_desktop_entry(entry: &str) -> String {
+    let (mut file, _) = DesktopFile::parse(entry);
+    file.normalize();
+    file.to_string()
+}
+
+/// returns `entry` with `StartupWMClass=wm_class` added to its `[Desktop Entry]` group,
+/// unless the group already sets one
+pub fn with_startup_wm_class(entry: &str, wm_class: &str) -> String {
+    let (mut file, _) = DesktopFile::parse(entry);
+    if file
+        .group("Desktop Entry")
+        .is_some_and(|x| x.get("StartupWMClass").is_none())
+    {
+        file.set(
+            "Desktop Entry",
+            "StartupWMClass",
+            &desktop_file::escape(wm_class),
+        );
+        file.normalize();
+    }
+    file.to_string()
+}
+
+/// key recording the owner a managed entry was registered by, if its lifetime has one
+pub const OWNER_KEY: &str = "X-DesktopEntryDaemon-Owner";
+/// key recording the lifetime a managed entry was registered with, in ron
+pub const LIFETIME_KEY: &str = "X-DesktopEntryDaemon-Lifetime";
+/// key recording every lifetime holding a claim on a managed entry shared by several, in ron
+pub const CLAIMS_KEY: &str = "X-DesktopEntryDaemon-Claims";
+/// key recording the app id a managed entry was registered as, which its file name doesn't
+/// match when the entry was namespaced
+pub const APPID_KEY: &str = "X-DesktopEntryDaemon-AppId";
+/// key recording when a managed entry was registered, in seconds since the unix epoch
+pub const REGISTERED_AT_KEY: &str = "X-DesktopEntryDaemon-RegisteredAt";
+
+/// the current wall-clock time, in seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// returns `entry` with keys added to its `[Desktop Entry]` group recording that the daemon
+/// manages it, as which `appid`, and for which owner and `lifetime`
+pub fn with_provenance(entry: &str, appid: &str, lifetime: &Lifetime) -> String {
+    let (mut file, _) = DesktopFile::parse(entry);
+    set_lifetime(&mut file, lifetime);
+    file.set("Desktop Entry", APPID_KEY, &desktop_file::escape(appid));
+    file.set("Desktop Entry", REGISTERED_AT_KEY, &unix_now().to_string());
+    file.normalize();
+    file.to_string()
+}
+
+/// returns `entry` with its provenance keys changed to record `lifetime`, keeping the time it
+/// was registered at
+pub fn with_lifetime(entry: &str, lifetime: &Lifetime) -> String {
+    let (mut file, _) = DesktopFile::parse(entry);
+    set_lifetime(&mut file, lifetime);
+    file.normalize();
+    file.to_string()
+}
+
+/// returns `entry` with its provenance keys changed to record the `claims` on it, kept for
+/// the claim `lifetime`, keeping the time it was registered at
+pub fn with_claims(entry: &str, lifetime: &Lifetime, claims: &[Lifetime]) -> String {
+    let (mut file, _) = DesktopFile::parse(entry);
+    set_lifetime(&mut file, lifetime);
+    match ron::to_string(claims) {
+        Ok(claims_str) if claims.len() > 1 => file.set("Desktop Entry", CLAIMS_KEY, &claims_str),
+        _ => file.remove("Desktop Entry", CLAIMS_KEY),
+    }
+    file.normalize();
+    file.to_string()
+}
+
+fn set_lifetime(file: &mut DesktopFile, lifetime: &Lifetime) {
+    match lifetime.owner() {
+        Some(owner) => file.set("Desktop Entry", OWNER_KEY, &desktop_file::escape(owner)),
+        None => file.remove("Desktop Entry", OWNER_KEY),
+    }
+    if let Ok(lifetime) = ron::to_string(lifetime) {
+        file.set("Desktop Entry", LIFETIME_KEY, &lifetime);
+    }
+}
+
+/// the lifetime recorded in the provenance keys of `entry`, if the daemon wrote it
+pub fn provenance(entry: &str) -> Option<Lifetime> {
+    let (file, _) = DesktopFile::parse(entry);
+    let value = &file.group("Desktop Entry")?.get(LIFETIME_KEY)?.value;
+    ron::from_str(value).ok()
+}
+
+/// the app id recorded in the provenance keys of `entry`, if the daemon wrote it
+pub fn recorded_appid(entry: &str) -> Option<String> {
+    let (file, _) = DesktopFile::parse(entry);
+    let value = &file.group("Desktop Entry")?.get(APPID_KEY)?.value;
+    Some(desktop_file::unescape(value))
+}
+
+/// every lifetime holding a claim on `entry`, recorded in its provenance keys if the daemon
+/// wrote it
+pub fn claims(entry: &str) -> Option<Vec<Lifetime>> {
+    let (file, _) = DesktopFile::parse(entry);
+    let group = file.group("Desktop Entry")?;
+    match group.get(CLAIMS_KEY) {
+        Some(claims) => ron::from_str(&claims.value).ok(),
+        None => ron::from_str(&group.get(LIFETIME_KEY)?.value)
+            .ok()
+            .map(|x| vec![x]),
     }
 }
 
-fn app_exists(id: &str) -> bool {
-    for path in Iter::new(default_paths()) {
-        if let Ok(bytes) = fs::read_to_string(&path) {
-            if let Ok(entry) = DesktopEntry::decode(&path, &bytes) {
-                if entry.appid == id {
-                    return true;
-                }
+/// validate an autostart entry. unlike application entries, autostart entries may share an
+/// appid with an installed application, but must have something to run
+/// whether `name` names a file in the directory it's joined to, rather than one elsewhere,
+/// i.e. through `../`, or a hidden one
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.starts_with('.')
+}
+
+pub fn validate_autostart_entry(entry: &str, appid: &str) -> Result<String, ValidationError> {
+    log::debug!("autostart appid: {}", appid);
+    log::trace!("entry: {}", entry);
+    if !is_file_name(appid) {
+        return Err(ValidationError::NotValid(format!(
+            "'{}' is not a valid app id",
+            appid
+        )));
+    }
+    match DesktopEntry::decode(Path::new(&format!("{}.desktop", appid)), entry) {
+        Err(e) => Err(ValidationError::NotValid(e.to_string())),
+        Ok(decoded) if decoded.exec().is_none() => Err(ValidationError::NotValid(
+            "autostart entry has no Exec key".to_string(),
+        )),
+        Ok(_) => Ok(normalize_desktop_entry(entry)),
+    }
+}
+
+/// validate a shared-mime-info package. the package must be well formed xml with a
+/// `mime-info` root element declaring at least one `mime-type`
+pub fn validate_mime_package(package: &str, name: &str) -> Result<String, String> {
+    log::debug!("mime package: {}", name);
+    log::trace!("package: {}", package);
+    if !is_file_name(name) {
+        return Err(format!("'{}' is not a valid package name", name));
+    }
+    let mut reader = Reader::from_str(package);
+    let mut depth = 0;
+    let mut root = None;
+    let mut mime_types = 0;
+    loop {
+        let (e, is_empty) = match reader.read_event() {
+            Ok(Event::Start(e)) => (e, false),
+            Ok(Event::Empty(e)) => (e, true),
+            Ok(Event::End(_)) => {
+                depth -= 1;
+                continue;
             }
+            Ok(Event::Eof) => break,
+            Ok(_) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        let element = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+        if depth == 0 && root.replace(element.clone()).is_some() {
+            return Err("package has more than one root element".to_string());
+        }
+        if depth == 1 && element == "mime-type" {
+            let has_type = e.attributes().flatten().any(|a| {
+                a.key.as_ref() == b"type"
+                    && String::from_utf8_lossy(&a.value).split('/').count() == 2
+            });
+            if !has_type {
+                return Err("mime-type element without a valid type".to_string());
+            }
+            mime_types += 1;
+        }
+        if !is_empty {
+            depth += 1;
+        }
+    }
+    match root.as_deref() {
+        Some("mime-info") if mime_types > 0 => Ok(package.to_string()),
+        Some("mime-info") => Err("package does not declare any mime types".to_string()),
+        _ => Err("package root element is not mime-info".to_string()),
+    }
+}
+
+/// validate a d-bus service activation file. the file must have a `[D-BUS Service]` group
+/// whose `Name` is `appid`, and an `Exec` or `SystemdService` to activate it with
+pub fn validate_dbus_service(service: &str, appid: &str) -> Result<String, String> {
+    log::debug!("d-bus service: {}", appid);
+    log::trace!("service: {}", service);
+    let mut group = None;
+    let mut name = None;
+    let mut has_exec = false;
+    for line in service.lines().map(|x| x.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            group = Some(&line[1..line.len() - 1]);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("'{}' is not a key or group header", line));
+        };
+        if group != Some("D-BUS Service") {
+            continue;
         }
+        match key.trim() {
+            "Name" => name = Some(value.trim()),
+            "Exec" | "SystemdService" => has_exec = true,
+            _ => {}
+        }
+    }
+    match name {
+        None => Err("service has no Name in a [D-BUS Service] group".to_string()),
+        Some(name) if name != appid => Err(format!(
+            "service name {} does not match the app id {}",
+            name, appid
+        )),
+        Some(_) if !has_exec => Err("service has no Exec or SystemdService".to_string()),
+        Some(_) => Ok(service.to_string()),
     }
-    false
+}
+
+/// run `update-mime-database` on a `mime` directory
+pub fn update_mime_database(mime_dir: &Path) -> std::io::Result<()> {
+    let status = Command::new("update-mime-database")
+        .arg(mime_dir)
+        .status()?;
+    if !status.success() {
+        log::warn!("update-mime-database exited with {}", status);
+    }
+    Ok(())
+}
+
+/// the user's autostart directory, `$XDG_CONFIG_HOME/autostart`
+pub fn get_autostart_dir() -> PathBuf {
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or(format!("{}/.config", env::var("HOME").unwrap()));
+    Path::new(&config_home).join("autostart")
+}
+
+/// the user's data directory, `$XDG_DATA_HOME`
+pub fn get_data_home() -> PathBuf {
+    let data_home = env::var("XDG_DATA_HOME")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or(format!("{}/.local/share", env::var("HOME").unwrap()));
+    PathBuf::from(data_home)
 }
 
 pub fn get_dirs() -> (PathBuf, PathBuf, PathBuf, PathBuf) {
@@ -93,3 +365,71 @@ pub fn get_dirs() -> (PathBuf, PathBuf, PathBuf, PathBuf) {
         config_file.to_owned(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::Severity;
+
+    const ENTRY: &str = "[Desktop Entry]\nType=Application\nName=Foo\nExec=foo\n";
+
+    #[test]
+    fn owner_cannot_inject_keys_or_groups() {
+        let owner = "me\nExec=evil\r\n[Desktop Action evil]\nExec=evil\\";
+        for lifetime in [
+            Lifetime::Session(owner.to_string()),
+            Lifetime::AnyOf(vec![
+                Lifetime::Process(1),
+                Lifetime::Persistent(owner.to_string()),
+            ]),
+        ] {
+            let written = with_provenance(ENTRY, "com.example.Foo", &lifetime);
+            let (file, errors) = DesktopFile::parse(&written);
+            assert!(errors.is_empty(), "{:?}", errors);
+            assert_eq!(file.groups.len(), 1, "{}", written);
+            let group = file.group("Desktop Entry").unwrap();
+            assert_eq!(group.get("Exec").unwrap().value, "foo");
+            assert_eq!(
+                group.get(OWNER_KEY).unwrap().value,
+                "me\\nExec=evil\\r\\n[Desktop Action evil]\\nExec=evil\\\\"
+            );
+            // the lifetime still reads back as the one that was written
+            assert_eq!(provenance(&written), Some(lifetime.clone()));
+            let written = with_claims(&written, &lifetime, &[lifetime.clone(), lifetime.clone()]);
+            assert_eq!(DesktopFile::parse(&written).0.groups.len(), 1);
+        }
+    }
+
+    #[test]
+    fn entries_with_problems_are_only_refused_when_configured() {
+        let entry = format!("{}Unknown=1\n", ENTRY);
+        assert!(validate_desktop_entry(&entry, "com.example.Foo", Threshold::default()).is_ok());
+        assert!(matches!(
+            validate_desktop_entry(&entry, "com.example.Foo", Threshold::Error),
+            Err(ValidationError::Diagnostics(_))
+        ));
+    }
+
+    #[test]
+    fn normalizing_keeps_lines_that_cannot_be_parsed() {
+        let entry = format!("{}not a key\nName[en=Foo\n", ENTRY);
+        let normalized = normalize_desktop_entry(&entry);
+        assert!(normalized.contains("\nnot a key\n"), "{}", normalized);
+        assert!(normalized.contains("\nName[en=Foo\n"), "{}", normalized);
+        assert_eq!(normalize_desktop_entry(&normalized), normalized);
+    }
+
+    #[test]
+    fn duplicate_keys_are_merged_rather_than_refused() {
+        let entry = format!("{}Name=Bar\n", ENTRY);
+        let (normalized, diagnostics) =
+            validate_desktop_entry(&entry, "com.example.Foo", Threshold::Error)
+                .expect("duplicate keys are only a warning");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        let (file, _) = DesktopFile::parse(&normalized);
+        let group = file.group("Desktop Entry").unwrap();
+        assert_eq!(group.entries.iter().filter(|x| x.key == "Name").count(), 1);
+        assert_eq!(group.get("Name").unwrap().value, "Bar");
+    }
+}
@@ -0,0 +1,765 @@
+use std::{collections::HashSet, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::desktop_file::{DesktopFile, Entry, Group};
+
+/// how bad a problem found in a desktop entry is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Severity {
+    /// the entry works, but doesn't follow the spec or uses something deprecated
+    Warning,
+    /// the entry breaks the spec, and desktops may ignore or misread it
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// a problem found in a desktop entry, and the line it was found on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.severity, self.message)
+    }
+}
+
+/// the least severe diagnostic that makes an entry get rejected, ordered from the most lenient
+/// to the strictest. the default is `Off`, so entries accepted before they were checked
+/// against the spec still are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Threshold {
+    /// only reject entries that can't be parsed at all
+    #[default]
+    Off,
+    /// reject entries with errors
+    Error,
+    /// reject entries with errors or warnings
+    Warning,
+}
+
+impl Threshold {
+    /// whether `diagnostic` makes an entry get rejected
+    pub fn rejects(&self, diagnostic: &Diagnostic) -> bool {
+        match self {
+            Threshold::Off => false,
+            Threshold::Error => diagnostic.severity >= Severity::Error,
+            Threshold::Warning => diagnostic.severity >= Severity::Warning,
+        }
+    }
+}
+
+/// key type, as defined in the desktop entry spec
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ValueType {
+    String,
+    LocaleString,
+    IconString,
+    Boolean,
+    Strings,
+    LocaleStrings,
+}
+
+impl ValueType {
+    fn localized(&self) -> bool {
+        matches!(
+            self,
+            ValueType::LocaleString | ValueType::IconString | ValueType::LocaleStrings
+        )
+    }
+
+    fn list(&self) -> bool {
+        matches!(self, ValueType::Strings | ValueType::LocaleStrings)
+    }
+}
+
+/// the keys registered in the `[Desktop Entry]` group
+const KEYS: &[(&str, ValueType)] = &[
+    ("Type", ValueType::String),
+    ("Version", ValueType::String),
+    ("Name", ValueType::LocaleString),
+    ("GenericName", ValueType::LocaleString),
+    ("NoDisplay", ValueType::Boolean),
+    ("Comment", ValueType::LocaleString),
+    ("Icon", ValueType::IconString),
+    ("Hidden", ValueType::Boolean),
+    ("OnlyShowIn", ValueType::Strings),
+    ("NotShowIn", ValueType::Strings),
+    ("DBusActivatable", ValueType::Boolean),
+    ("TryExec", ValueType::String),
+    ("Exec", ValueType::String),
+    ("Path", ValueType::String),
+    ("Terminal", ValueType::Boolean),
+    ("Actions", ValueType::Strings),
+    ("MimeType", ValueType::Strings),
+    ("Categories", ValueType::Strings),
+    ("Implements", ValueType::Strings),
+    ("Keywords", ValueType::LocaleStrings),
+    ("StartupNotify", ValueType::Boolean),
+    ("StartupWMClass", ValueType::String),
+    ("URL", ValueType::String),
+    ("PrefersNonDefaultGPU", ValueType::Boolean),
+    ("SingleMainWindow", ValueType::Boolean),
+];
+
+/// the keys registered in `[Desktop Action]` groups
+const ACTION_KEYS: &[(&str, ValueType)] = &[
+    ("Name", ValueType::LocaleString),
+    ("Icon", ValueType::IconString),
+    ("Exec", ValueType::String),
+];
+
+/// keys from older versions of the spec, still tolerated with a warning
+const DEPRECATED_KEYS: &[&str] = &[
+    "Encoding",
+    "MiniIcon",
+    "TerminalOptions",
+    "Protocols",
+    "Extensions",
+    "BinaryPattern",
+    "MapNotify",
+    "SwallowTitle",
+    "SwallowExec",
+    "SortOrder",
+    "FilePattern",
+];
+
+/// the main and additional categories registered in the desktop menu spec
+const CATEGORIES: &[&str] = &[
+    "AudioVideo",
+    "Audio",
+    "Video",
+    "Development",
+    "Education",
+    "Game",
+    "Graphics",
+    "Network",
+    "Office",
+    "Science",
+    "Settings",
+    "System",
+    "Utility",
+    "Building",
+    "Debugger",
+    "IDE",
+    "GUIDesigner",
+    "Profiling",
+    "RevisionControl",
+    "Translation",
+    "Calendar",
+    "ContactManagement",
+    "Database",
+    "Dictionary",
+    "Chart",
+    "Email",
+    "Finance",
+    "FlowChart",
+    "PDA",
+    "ProjectManagement",
+    "Presentation",
+    "Spreadsheet",
+    "WordProcessor",
+    "2DGraphics",
+    "VectorGraphics",
+    "RasterGraphics",
+    "3DGraphics",
+    "Scanning",
+    "OCR",
+    "Photography",
+    "Publishing",
+    "Viewer",
+    "TextTools",
+    "DesktopSettings",
+    "HardwareSettings",
+    "Printing",
+    "PackageManager",
+    "Dialup",
+    "InstantMessaging",
+    "Chat",
+    "IRCClient",
+    "Feed",
+    "FileTransfer",
+    "HamRadio",
+    "News",
+    "P2P",
+    "RemoteAccess",
+    "Telephony",
+    "TelephonyTools",
+    "VideoConference",
+    "WebBrowser",
+    "WebDevelopment",
+    "Midi",
+    "Mixer",
+    "Sequencer",
+    "Tuner",
+    "TV",
+    "AudioVideoEditing",
+    "Player",
+    "Recorder",
+    "DiscBurning",
+    "ActionGame",
+    "AdventureGame",
+    "ArcadeGame",
+    "BoardGame",
+    "BlocksGame",
+    "CardGame",
+    "KidsGame",
+    "LogicGame",
+    "RolePlaying",
+    "Shooter",
+    "Simulation",
+    "SportsGame",
+    "StrategyGame",
+    "Art",
+    "Construction",
+    "Music",
+    "Languages",
+    "ArtificialIntelligence",
+    "Astronomy",
+    "Biology",
+    "Chemistry",
+    "ComputerScience",
+    "DataVisualization",
+    "Economy",
+    "Electricity",
+    "Geography",
+    "Geology",
+    "Geoscience",
+    "History",
+    "Humanities",
+    "ImageProcessing",
+    "Literature",
+    "Maps",
+    "Math",
+    "NumericalAnalysis",
+    "MedicalSoftware",
+    "Physics",
+    "Robotics",
+    "Spirituality",
+    "Sports",
+    "ParallelComputing",
+    "Amusement",
+    "Archiving",
+    "Compression",
+    "Electronics",
+    "Emulator",
+    "Engineering",
+    "FileTools",
+    "FileManager",
+    "TerminalEmulator",
+    "Filesystem",
+    "Monitor",
+    "Security",
+    "Accessibility",
+    "Calculator",
+    "Clock",
+    "TextEditor",
+    "Documentation",
+    "Adult",
+    "Core",
+    "KDE",
+    "GNOME",
+    "XFCE",
+    "DDE",
+    "GTK",
+    "Qt",
+    "Motif",
+    "Java",
+    "ConsoleOnly",
+];
+
+const ENTRY_GROUP: &str = "Desktop Entry";
+const ACTION_PREFIX: &str = "Desktop Action ";
+
+/// the position of `key` among the registered keys of `group`, in spec order
+pub fn key_rank(group: &str, key: &str) -> Option<usize> {
+    let keys = if group == ENTRY_GROUP {
+        KEYS
+    } else if group.starts_with(ACTION_PREFIX) {
+        ACTION_KEYS
+    } else {
+        return None;
+    };
+    keys.iter().position(|(x, _)| *x == key)
+}
+
+/// checks `entry` against the desktop entry spec, the way `desktop-file-validate` does,
+/// returning every problem found, in line order
+pub fn validate(entry: &str) -> Vec<Diagnostic> {
+    let (file, parse_errors) = DesktopFile::parse(entry);
+    let mut diagnostics = parse_errors
+        .into_iter()
+        .map(|x| Diagnostic {
+            line: x.line,
+            severity: Severity::Error,
+            message: x.message,
+        })
+        .collect::<Vec<_>>();
+    let mut report = |line: usize, severity: Severity, message: String| {
+        diagnostics.push(Diagnostic {
+            line,
+            severity,
+            message,
+        })
+    };
+    match file.groups.first() {
+        None => report(
+            1,
+            Severity::Error,
+            format!("file has no [{}] group", ENTRY_GROUP),
+        ),
+        Some(group) if group.name != ENTRY_GROUP => report(
+            group.line,
+            Severity::Error,
+            format!("first group is [{}], not [{}]", group.name, ENTRY_GROUP),
+        ),
+        Some(_) => {}
+    }
+    let mut seen_groups = HashSet::new();
+    for group in &file.groups {
+        if !seen_groups.insert(group.name.as_str()) {
+            report(
+                group.line,
+                Severity::Warning,
+                format!(
+                    "group [{}] is defined more than once, and will be merged",
+                    group.name
+                ),
+            );
+        }
+        if group.name.contains(['[', ']']) || group.name.chars().any(|x| x.is_control()) {
+            report(
+                group.line,
+                Severity::Error,
+                format!("group name [{}] contains invalid characters", group.name),
+            );
+        }
+        let keys = if group.name == ENTRY_GROUP {
+            Some(KEYS)
+        } else if group.name.starts_with(ACTION_PREFIX) {
+            Some(ACTION_KEYS)
+        } else {
+            if !group.name.starts_with("X-") {
+                report(
+                    group.line,
+                    Severity::Error,
+                    format!(
+                        "group [{}] is not registered and doesn't start with X-",
+                        group.name
+                    ),
+                );
+            }
+            None
+        };
+        validate_keys(group, keys, &mut report);
+    }
+    // the rest is checked against what gets written, with groups and keys set more than once
+    // merged the same way
+    let mut merged = file.clone();
+    merged.normalize();
+    if let Some(group) = merged.group(ENTRY_GROUP) {
+        validate_entry_group(&merged, group, &mut report);
+    }
+    diagnostics.sort_by_key(|x| x.line);
+    diagnostics
+}
+
+/// checks the syntax of the keys and values in `group`. `keys` are the registered keys of
+/// the group, or `None` for extension groups, which may contain anything
+fn validate_keys(
+    group: &Group,
+    keys: Option<&[(&str, ValueType)]>,
+    report: &mut impl FnMut(usize, Severity, String),
+) {
+    let mut seen = HashSet::new();
+    for entry in &group.entries {
+        if !seen.insert((entry.key.as_str(), entry.locale.as_deref())) {
+            report(
+                entry.line,
+                Severity::Warning,
+                format!(
+                    "key {} is set more than once in [{}], and the last value will be kept",
+                    full_key(entry),
+                    group.name
+                ),
+            );
+        }
+        if entry.key.is_empty()
+            || !entry
+                .key
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-')
+        {
+            report(
+                entry.line,
+                Severity::Error,
+                format!("'{}' is not a valid key name", entry.key),
+            );
+            continue;
+        }
+        if let Some(locale) = &entry.locale {
+            if !valid_locale(locale) {
+                report(
+                    entry.line,
+                    Severity::Error,
+                    format!("'{}' is not a valid locale", locale),
+                );
+            }
+        }
+        let Some(keys) = keys else {
+            continue;
+        };
+        if entry.key.starts_with("X-") {
+            continue;
+        }
+        let Some((_, value_type)) = keys.iter().find(|(key, _)| *key == entry.key) else {
+            if group.name == ENTRY_GROUP && DEPRECATED_KEYS.contains(&entry.key.as_str()) {
+                report(
+                    entry.line,
+                    Severity::Warning,
+                    format!("key {} is deprecated", entry.key),
+                );
+            } else {
+                report(
+                    entry.line,
+                    Severity::Error,
+                    format!(
+                        "key {} is not registered for [{}] and doesn't start with X-",
+                        entry.key, group.name
+                    ),
+                );
+            }
+            continue;
+        };
+        if entry.locale.is_some() && !value_type.localized() {
+            report(
+                entry.line,
+                Severity::Error,
+                format!("key {} can't be localized", entry.key),
+            );
+        }
+        validate_value(entry, *value_type, report);
+    }
+}
+
+/// checks that the value of `entry` is a valid `value_type`
+fn validate_value(
+    entry: &Entry,
+    value_type: ValueType,
+    report: &mut impl FnMut(usize, Severity, String),
+) {
+    if value_type != ValueType::LocaleString
+        && value_type != ValueType::LocaleStrings
+        && !entry.value.is_ascii()
+    {
+        report(
+            entry.line,
+            Severity::Error,
+            format!("value of {} contains non-ascii characters", full_key(entry)),
+        );
+    }
+    if let Some(escape) = invalid_escape(&entry.value) {
+        report(
+            entry.line,
+            Severity::Error,
+            format!("value of {} contains {}", full_key(entry), escape),
+        );
+    }
+    if value_type == ValueType::Boolean && entry.value != "true" && entry.value != "false" {
+        report(
+            entry.line,
+            Severity::Error,
+            format!(
+                "value '{}' of {} is not a boolean, use true or false",
+                entry.value,
+                full_key(entry)
+            ),
+        );
+    }
+    if value_type.list() && !entry.value.is_empty() && !entry.value.ends_with(';') {
+        report(
+            entry.line,
+            Severity::Warning,
+            format!(
+                "value of {} is a list and should end with a semicolon",
+                full_key(entry)
+            ),
+        );
+    }
+}
+
+/// checks the keys of the `[Desktop Entry]` group against each other, and against the
+/// action groups of the file
+fn validate_entry_group(
+    file: &DesktopFile,
+    group: &Group,
+    report: &mut impl FnMut(usize, Severity, String),
+) {
+    let entry_type = group.get("Type");
+    match entry_type.map(|x| x.value.as_str()) {
+        None => report(
+            group.line,
+            Severity::Error,
+            "required key Type is missing".to_string(),
+        ),
+        Some("Application" | "Link" | "Directory") => {}
+        Some(value) => report(
+            entry_type.unwrap().line,
+            Severity::Error,
+            format!("'{}' is not a valid Type", value),
+        ),
+    }
+    if group.get("Name").is_none() {
+        report(
+            group.line,
+            Severity::Error,
+            "required key Name is missing".to_string(),
+        );
+    }
+    match entry_type.map(|x| x.value.as_str()) {
+        Some("Application") => {
+            let dbus_activatable = group
+                .get("DBusActivatable")
+                .is_some_and(|x| x.value == "true");
+            if group.get("Exec").is_none() && !dbus_activatable {
+                report(
+                    group.line,
+                    Severity::Error,
+                    "required key Exec is missing for an Application".to_string(),
+                );
+            }
+        }
+        Some("Link") if group.get("URL").is_none() => report(
+            group.line,
+            Severity::Error,
+            "required key URL is missing for a Link".to_string(),
+        ),
+        _ => {}
+    }
+    if let Some(version) = group.get("Version") {
+        if !["1.0", "1.1", "1.2", "1.3", "1.4", "1.5"].contains(&version.value.as_str()) {
+            report(
+                version.line,
+                Severity::Warning,
+                format!("'{}' is not a known spec Version", version.value),
+            );
+        }
+    }
+    if let (Some(_), Some(not_show_in)) = (group.get("OnlyShowIn"), group.get("NotShowIn")) {
+        report(
+            not_show_in.line,
+            Severity::Error,
+            "OnlyShowIn and NotShowIn can't both be set".to_string(),
+        );
+    }
+    if let Some(categories) = group.get("Categories") {
+        for category in list(&categories.value) {
+            if !category.starts_with("X-") && !CATEGORIES.contains(&category) {
+                report(
+                    categories.line,
+                    Severity::Error,
+                    format!(
+                        "'{}' is not a registered category and doesn't start with X-",
+                        category
+                    ),
+                );
+            }
+        }
+    }
+    if let Some(mime_types) = group.get("MimeType") {
+        for mime_type in list(&mime_types.value) {
+            let valid = mime_type.split_once('/').is_some_and(|(media, sub)| {
+                !media.is_empty() && !sub.is_empty() && !sub.contains('/')
+            });
+            if !valid {
+                report(
+                    mime_types.line,
+                    Severity::Error,
+                    format!("'{}' is not a valid mime type", mime_type),
+                );
+            }
+        }
+    }
+    let actions = group
+        .get("Actions")
+        .map(|x| list(&x.value).collect::<Vec<_>>())
+        .unwrap_or_default();
+    for action in &actions {
+        if file
+            .group(&format!("{}{}", ACTION_PREFIX, action))
+            .is_none()
+        {
+            report(
+                group.get("Actions").unwrap().line,
+                Severity::Error,
+                format!(
+                    "action '{}' has no [{}{}] group",
+                    action, ACTION_PREFIX, action
+                ),
+            );
+        }
+    }
+    for action_group in &file.groups {
+        let Some(action) = action_group.name.strip_prefix(ACTION_PREFIX) else {
+            continue;
+        };
+        if !actions.contains(&action) {
+            report(
+                action_group.line,
+                Severity::Warning,
+                format!(
+                    "action '{}' is not listed in Actions, and will be ignored",
+                    action
+                ),
+            );
+        }
+        if action_group.get("Name").is_none() {
+            report(
+                action_group.line,
+                Severity::Error,
+                format!("required key Name is missing for action '{}'", action),
+            );
+        }
+    }
+}
+
+/// the items of a string list value
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(';').map(str::trim).filter(|x| !x.is_empty())
+}
+
+/// `key` or `key[locale]`, as the key was written
+fn full_key(entry: &Entry) -> String {
+    match &entry.locale {
+        Some(locale) => format!("{}[{}]", entry.key, locale),
+        None => entry.key.clone(),
+    }
+}
+
+/// whether `locale` matches `lang_COUNTRY.ENCODING@MODIFIER`, where everything but `lang`
+/// is optional
+fn valid_locale(locale: &str) -> bool {
+    let (rest, modifier) = match locale.split_once('@') {
+        Some((rest, modifier)) => (rest, Some(modifier)),
+        None => (locale, None),
+    };
+    let (rest, encoding) = match rest.split_once('.') {
+        Some((rest, encoding)) => (rest, Some(encoding)),
+        None => (rest, None),
+    };
+    let (lang, country) = match rest.split_once('_') {
+        Some((lang, country)) => (lang, Some(country)),
+        None => (rest, None),
+    };
+    let part = |x: &str| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric());
+    !lang.is_empty()
+        && lang.chars().all(|c| c.is_ascii_lowercase())
+        && country.is_none_or(part)
+        && encoding.is_none_or(|x| {
+            !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && modifier.is_none_or(part)
+}
+
+/// describes the first escape sequence in `value` that the spec doesn't define, if any
+fn invalid_escape(value: &str) -> Option<String> {
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('s' | 'n' | 't' | 'r' | '\\' | ';') => {}
+                Some(other) => return Some(format!("the invalid escape sequence \\{}", other)),
+                None => return Some("a trailing backslash".to_string()),
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = "[Desktop Entry]\nType=Application\nName=Foo\nExec=foo\n";
+
+    fn diagnostic(severity: Severity) -> Diagnostic {
+        Diagnostic {
+            line: 1,
+            severity,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn valid_entries_have_no_diagnostics() {
+        assert_eq!(validate(ENTRY), Vec::new());
+        let entry = format!(
+            "{}Categories=Utility;\nActions=new;\n\n[Desktop Action new]\nName=New\n",
+            ENTRY
+        );
+        assert_eq!(validate(&entry), Vec::new());
+    }
+
+    #[test]
+    fn problems_are_reported_with_their_severity_and_line() {
+        let entry = "[Desktop Entry]\nType=Application\nExec=foo\nEncoding=UTF-8\nNoDisplay=yes\n";
+        let diagnostics = validate(entry);
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|x| (x.line, x.severity))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, Severity::Error),
+                (4, Severity::Warning),
+                (5, Severity::Error)
+            ]
+        );
+        assert!(diagnostics[0].message.contains("Name"));
+        assert!(diagnostics[1].message.contains("deprecated"));
+        assert!(diagnostics[2].message.contains("boolean"));
+    }
+
+    #[test]
+    fn duplicate_keys_are_checked_by_their_last_value() {
+        let entry = format!("{}Type=Foo\n", ENTRY);
+        let diagnostics = validate(&entry);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            (diagnostics[0].line, diagnostics[0].severity),
+            (5, Severity::Warning)
+        );
+        assert_eq!(
+            (diagnostics[1].line, diagnostics[1].severity),
+            (5, Severity::Error)
+        );
+        let entry = "[Desktop Entry]\nType=Foo\nType=Application\nName=Foo\nExec=foo\n";
+        let diagnostics = validate(entry);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn thresholds_reject_diagnostics_at_least_as_severe() {
+        let warning = diagnostic(Severity::Warning);
+        let error = diagnostic(Severity::Error);
+        assert!(!Threshold::Off.rejects(&warning));
+        assert!(!Threshold::Off.rejects(&error));
+        assert!(!Threshold::Error.rejects(&warning));
+        assert!(Threshold::Error.rejects(&error));
+        assert!(Threshold::Warning.rejects(&warning));
+        assert!(Threshold::Warning.rejects(&error));
+    }
+}